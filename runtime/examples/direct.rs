@@ -10,7 +10,9 @@ use tokio::{
     process::{Child, Command},
     sync,
 };
-use ya_runtime_vm::guest_agent_comm::{GuestAgent, Notification, RedirectFdType};
+use ya_runtime_vm::guest_agent_comm::{
+    GuestAgent, Notification, RedirectFdType, Timeouts, SIGKILL,
+};
 
 struct Notifications {
     process_died: sync::Notify,
@@ -40,7 +42,7 @@ impl Notifications {
 }
 
 async fn run_process_with_output(
-    ga: &mut GuestAgent,
+    ga: &GuestAgent,
     notifications: &Notifications,
     bin: &str,
     argv: &[&str],
@@ -59,17 +61,16 @@ async fn run_process_with_output(
             ],
             None,
         )
-        .await?
-        .expect("Run process failed");
+        .await?;
     println!("Spawned process with id: {}", id);
     notifications.process_died.notified().await;
     notifications.output_available.notified().await;
-    match ga.query_output(id, 1, 0, u64::MAX).await? {
+    match ga.query_output(id, 1, 0, u64::MAX).await {
         Ok(out) => {
             println!("Output:");
-            io::stdout().write_all(&out)?;
+            io::stdout().write_all(&out.data)?;
         }
-        Err(code) => println!("Output query failed with: {}", code),
+        Err(err) => println!("Output query failed with: {}", err),
     }
     Ok(())
 }
@@ -169,19 +170,20 @@ async fn main() -> io::Result<()> {
     let mut child = spawn_vm(&temp_path, &mount_args);
 
     let ns = notifications.clone();
-    let ga_mutex = GuestAgent::connected(temp_path.join("manager.sock"), 10, move |n, _g| {
-        let notifications = ns.clone();
-        async move { notifications.clone().handle(n) }.boxed()
-    })
+    let ga = GuestAgent::connected(
+        temp_path.join("manager.sock"),
+        Timeouts::default(),
+        move |n, _g| {
+            let notifications = ns.clone();
+            async move { notifications.clone().handle(n) }.boxed()
+        },
+    )
     .await?;
-    let mut ga = ga_mutex.lock().await;
 
     let no_redir = [None, None, None];
 
     for (i, (tag, _)) in mount_args.iter().enumerate() {
-        ga.mount(tag, &format!("/mnt/mnt{}/{}", i, tag))
-            .await?
-            .expect("Mount failed");
+        ga.mount(tag, &format!("/mnt/mnt{}/{}", i, tag)).await?;
     }
 
     let id = ga
@@ -194,19 +196,15 @@ async fn main() -> io::Result<()> {
             &no_redir,
             Some("/mnt"),
         )
-        .await?
-        .expect("Run process failed");
+        .await?;
     println!("Spawned process with id: {}", id);
     notifications.process_died.notified().await;
-    let out = ga
-        .query_output(id, 1, 0, u64::MAX)
-        .await?
-        .expect("Output query failed");
+    let out = ga.query_output(id, 1, 0, u64::MAX).await?.data;
     println!("Output:");
     io::stdout().write_all(&out)?;
 
     run_process_with_output(
-        &mut ga,
+        &ga,
         &notifications,
         "/bin/ls",
         &["ls", "-al", "/mnt/mnt1/tag1"],
@@ -222,13 +220,12 @@ async fn main() -> io::Result<()> {
     ];
     let id = ga
         .run_process("/bin/echo", &["echo", "WRITE TEST"], None, 0, 0, &fds, None)
-        .await?
-        .expect("Run process failed");
+        .await?;
     println!("Spawned process with id: {}", id);
     notifications.process_died.notified().await;
 
     run_process_with_output(
-        &mut ga,
+        &ga,
         &notifications,
         "/bin/cat",
         &["cat", "/mnt/mnt1/tag1/write_test"],
@@ -237,11 +234,10 @@ async fn main() -> io::Result<()> {
 
     let id = ga
         .run_process("/bin/sleep", &["sleep", "10"], None, 0, 0, &no_redir, None)
-        .await?
-        .expect("Run process failed");
+        .await?;
     println!("Spawned process with id: {}", id);
 
-    ga.kill(id).await?.expect("Kill failed");
+    ga.kill(id, SIGKILL, None).await?;
     notifications.process_died.notified().await;
 
     let id = ga
@@ -262,24 +258,17 @@ async fn main() -> io::Result<()> {
             ],
             None,
         )
-        .await?
-        .expect("Run process failed");
+        .await?;
     println!("Spawned process with id: {}", id);
     notifications.output_available.notified().await;
-    let out = ga
-        .query_output(id, 1, 0, u64::MAX)
-        .await?
-        .expect("Output query failed");
+    let out = ga.query_output(id, 1, 0, u64::MAX).await?.data;
     println!(
         "Big output 1: {} {}",
         out.len(),
         out.iter().filter(|x| **x != 0x61).count()
     );
     notifications.output_available.notified().await;
-    let out = ga
-        .query_output(id, 1, 0, u64::MAX)
-        .await?
-        .expect("Output query failed");
+    let out = ga.query_output(id, 1, 0, u64::MAX).await?.data;
     println!(
         "Big output 2: {} {}",
         out.len(),
@@ -304,32 +293,24 @@ async fn main() -> io::Result<()> {
             ],
             None,
         )
-        .await?
-        .expect("Run process failed");
+        .await?;
     println!("Spawned process with id: {}", id);
     notifications.process_died.notified().await;
     notifications.output_available.notified().await;
-    let out = ga
-        .query_output(id, 1, 0, u64::MAX)
-        .await?
-        .expect("Output query failed");
+    let out = ga.query_output(id, 1, 0, u64::MAX).await?.data;
     println!(
         "Big output 1: {} {}",
         out.len(),
         out.iter().filter(|x| **x != 0x62).count()
     );
-    let out = ga
-        .query_output(id, 1, 0, u64::MAX)
-        .await?
-        .expect("Output query failed");
+    let out = ga.query_output(id, 1, 0, u64::MAX).await?.data;
     println!("Big output 2: {}, expected 0", out.len());
 
     // ga.quit().await?.expect("Quit failed");
 
     let id = ga
         .run_entrypoint("/bin/sleep", &["sleep", "2"], None, 0, 0, &no_redir, None)
-        .await?
-        .expect("Run process failed");
+        .await?;
     println!("Spawned process with id: {}", id);
     notifications.process_died.notified().await;
 
@@ -23,7 +23,7 @@ use tokio::{
     sync,
 };
 use ya_runtime_sdk::runtime_api::server;
-use ya_runtime_vm::guest_agent_comm::{GuestAgent, Notification, RedirectFdType};
+use ya_runtime_vm::guest_agent_comm::{GuestAgent, Notification, RedirectFdType, Timeouts};
 
 const IDENTIFICATION: AtomicU16 = AtomicU16::new(42);
 const MTU: usize = 1400;
@@ -31,7 +31,7 @@ const PREFIX_LEN: usize = 2;
 
 struct Notifications {
     process_died: sync::Notify,
-    ga: Option<Arc<Mutex<GuestAgent>>>,
+    ga: Option<Arc<GuestAgent>>,
 }
 
 impl Notifications {
@@ -42,7 +42,7 @@ impl Notifications {
         }
     }
 
-    fn set_ga(&mut self, ga: Arc<Mutex<GuestAgent>>) {
+    fn set_ga(&mut self, ga: Arc<GuestAgent>) {
         self.ga.replace(ga);
     }
 
@@ -55,17 +55,9 @@ impl Notifications {
                 };
 
                 tokio::spawn(async move {
-                    match ga
-                        .lock()
-                        .await
-                        .query_output(id, fd as u8, 0u64, u64::MAX)
-                        .await
-                    {
-                        Ok(res) => match res {
-                            Ok(out) => while let Err(_) = io::stdout().write_all(&out[..]) {},
-                            Err(code) => eprintln!("Output query failed with: {}", code),
-                        },
-                        Err(code) => eprintln!("Output query failed with: {}", code),
+                    match ga.query_output(id, fd as u8, 0u64, u64::MAX).await {
+                        Ok(out) => while let Err(_) = io::stdout().write_all(&out.data[..]) {},
+                        Err(err) => eprintln!("Output query failed with: {}", err),
                     }
                 });
             }
@@ -77,7 +69,7 @@ impl Notifications {
     }
 }
 
-async fn run_process(ga: &mut GuestAgent, bin: &str, argv: &[&str]) -> io::Result<()> {
+async fn run_process(ga: &GuestAgent, bin: &str, argv: &[&str]) -> io::Result<()> {
     let id = ga
         .run_process(
             bin,
@@ -92,8 +84,7 @@ async fn run_process(ga: &mut GuestAgent, bin: &str, argv: &[&str]) -> io::Resul
             ],
             None,
         )
-        .await?
-        .expect("Run process failed");
+        .await?;
     eprintln!("Spawned process with id: {}", id);
     Ok(())
 }
@@ -379,14 +370,18 @@ async fn main() -> anyhow::Result<()> {
     let mut child = spawn_vm(&temp_path);
 
     let ns = notifications.clone();
-    let ga_mutex = GuestAgent::connected(temp_path.join("manager.sock"), 10, move |n, _g| {
-        let notifications = ns.clone();
-        async move { notifications.clone().lock().await.handle(n) }.boxed()
-    })
+    let ga = GuestAgent::connected(
+        temp_path.join("manager.sock"),
+        Timeouts::default(),
+        move |n, _g| {
+            let notifications = ns.clone();
+            async move { notifications.clone().lock().await.handle(n) }.boxed()
+        },
+    )
     .await?;
 
     {
-        notifications.clone().lock().await.set_ga(ga_mutex.clone());
+        notifications.clone().lock().await.set_ga(ga.clone());
     };
 
     handle_net(temp_path.join("net.sock")).await?;
@@ -398,32 +393,31 @@ async fn main() -> anyhow::Result<()> {
             .map(|(h, i)| (h.to_string(), i.to_string()))
             .collect::<Vec<_>>();
 
-        let mut ga = ga_mutex.lock().await;
-        match ga.add_address("10.0.0.1", "255.255.255.0", iface).await? {
-            Ok(_) | Err(0) => (),
-            Err(code) => anyhow::bail!("Unable to set address {}", code),
+        match ga.add_address("10.0.0.1", "255.255.255.0", iface).await {
+            Ok(_) => (),
+            Err(err) if err.errno() == Some(0) => (),
+            Err(err) => anyhow::bail!("Unable to set address: {}", err),
         }
         match ga
             .create_network("10.0.0.0", "255.255.255.0", "10.0.0.1", iface)
-            .await?
+            .await
         {
-            Ok(_) | Err(0) => (),
-            Err(code) => anyhow::bail!("Unable to join network {}", code),
+            Ok(_) => (),
+            Err(err) if err.errno() == Some(0) => (),
+            Err(err) => anyhow::bail!("Unable to join network: {}", err),
         }
-        match ga.add_hosts(hosts.into_iter()).await? {
-            Ok(_) | Err(0) => (),
-            Err(code) => anyhow::bail!("Unable to add hosts {}", code),
+        match ga.add_hosts(hosts.into_iter()).await {
+            Ok(_) => (),
+            Err(err) if err.errno() == Some(0) => (),
+            Err(err) => anyhow::bail!("Unable to add hosts: {}", err),
         }
     }
-    {
-        let mut ga = ga_mutex.lock().await;
-        run_process(
-            &mut ga,
-            "/bin/ping",
-            &["ping", "-v", "-n", "-D", "-c", "3", "10.0.0.2"],
-        )
-        .await?;
-    }
+    run_process(
+        &ga,
+        "/bin/ping",
+        &["ping", "-v", "-n", "-D", "-c", "3", "10.0.0.2"],
+    )
+    .await?;
 
     /* VM should quit now. */
     let e = child.wait().await.expect("failed to wait on child");
@@ -61,4 +61,18 @@ ssize_t cyclic_buffer_read(int fd, struct cyclic_buffer* cb, size_t count);
  */
 ssize_t cyclic_buffer_write(int fd, struct cyclic_buffer* cb, size_t count);
 
+/*
+ * Copies at most `count` bytes from `data` into the buffer.
+ * Unlike `cyclic_buffer_read` this never overwrites stored data, copying only
+ * as much as fits in the free space.
+ * Returns the number of bytes copied.
+ */
+size_t cyclic_buffer_append(struct cyclic_buffer* cb, const char* data, size_t count);
+
+/*
+ * Drops at most `count` bytes of the least recently stored data.
+ * Returns the number of bytes dropped.
+ */
+size_t cyclic_buffer_discard(struct cyclic_buffer* cb, size_t count);
+
 #endif // _CYCLIC_BUFFER_H
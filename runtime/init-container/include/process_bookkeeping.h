@@ -8,6 +8,8 @@
 #include "cyclic_buffer.h"
 #include "proto.h"
 
+struct epoll_fd_desc;
+
 struct redir_fd_desc {
     enum REDIRECT_FD_TYPE type;
     union {
@@ -17,6 +19,12 @@ struct redir_fd_desc {
         struct {
             struct cyclic_buffer cb;
             int fds[2];
+            /* Only for fd 0: close the pipe once the buffer gets drained. */
+            bool eof;
+            /* Only for fd 0: epoll registration, present while input is pending. */
+            struct epoll_fd_desc* epoll_desc;
+            /* Offset of the oldest buffered byte in the output stream. */
+            uint64_t offset;
         } buffer;
     };
 };
@@ -55,10 +55,15 @@ enum HOST_MSG_TYPE {
     /* Expected response: RESP_OK */
     MSG_UPLOAD_FILE,
 
-    /* Expected response: RESP_OK_BYTES - chunk of process' output */
+    /* Expected response: RESP_OK_OUTPUT - chunk of process' output
+     * Data read from a pipe is consumed. Any gap between the end of the
+     * previously returned chunk and the offset of this one was dropped. */
     MSG_QUERY_OUTPUT,
 
-    /* Expected response: RESP_OK */
+    /* Expected response: RESP_OK
+     * Requires fd 0 of the process to be redirected to a pipe. Data is
+     * accepted only if it fits in the pipe buffer as a whole, otherwise
+     * RESP_ERR with EAGAIN is sent. */
     MSG_PUT_INPUT,
 
     /* Expected response: RESP_OK */
@@ -69,6 +74,10 @@ enum HOST_MSG_TYPE {
 
     /* Expected response: RESP_OK */
     MSG_NET_HOST,
+
+    /* Expected response: RESP_OK
+     * Liveness check, has no side effects. */
+    MSG_PING,
 };
 
 enum SUB_MSG_QUIT_TYPE {
@@ -104,6 +113,11 @@ enum SUB_MSG_KILL_PROCESS_TYPE {
     SUB_MSG_KILL_PROCESS_END = 0,
     /* ID of process. (u64) */
     SUB_MSG_KILL_PROCESS_ID,
+    /* Signal to send, defaults to SIGKILL. (u32) */
+    SUB_MSG_KILL_PROCESS_SIGNAL,
+    /* Grace period in milliseconds, after which a process that is still
+     * alive gets SIGKILL. Zero (default) disables escalation. (u32) */
+    SUB_MSG_KILL_PROCESS_GRACE,
 };
 
 enum SUB_MSG_MOUNT_VOLUME_TYPE {
@@ -128,6 +142,10 @@ enum SUB_MSG_UPLOAD_FILE_TYPE {
     SUB_MSG_UPLOAD_FILE_GRP,
     /* Data to put into file. (BYTES) */
     SUB_MSG_UPLOAD_FILE_DATA,
+    /* Offset in the file at which data is written. (u64)
+     * Zero (default) creates or truncates the file and applies permissions
+     * and ownership, non-zero values continue a previous upload. */
+    SUB_MSG_UPLOAD_FILE_OFFSET,
 };
 
 enum SUB_MSG_QUERY_OUTPUT_TYPE {
@@ -137,7 +155,7 @@ enum SUB_MSG_QUERY_OUTPUT_TYPE {
     SUB_MSG_QUERY_OUTPUT_ID,
     /* File descriptor (u8) */
     SUB_MSG_QUERY_OUTPUT_FD,
-    /* Offset in output (default = 0). (u64) */
+    /* Offset in output (default = 0), ignored for pipes. (u64) */
     SUB_MSG_QUERY_OUTPUT_OFF,
     /* Requested length. (u64) */
     SUB_MSG_QUERY_OUTPUT_LEN,
@@ -148,10 +166,16 @@ enum SUB_MSG_PUT_INPUT_TYPE {
     SUB_MSG_PUT_INPUT_END = 0,
     /* ID of process. (u64) */
     SUB_MSG_PUT_INPUT_ID,
-    /* Data to put on process' stdin. (BYTES) */
+    /* Data to put on process' stdin; empty data closes stdin once all
+     * previously put data was consumed. (BYTES) */
     SUB_MSG_PUT_INPUT_DATA,
 };
 
+enum SUB_MSG_SYNC_FS_TYPE {
+    /* End of sub-messages. */
+    SUB_MSG_SYNC_FS_END = 0,
+};
+
 enum SUB_MSG_NET_CTL {
     /* End of sub-messages. */
     SUB_MSG_NET_CTL_END = 0,
@@ -186,6 +210,11 @@ enum SUB_MSG_NET_HOST {
     SUB_MSG_NET_HOST_ENTRY,
 };
 
+enum SUB_MSG_PING_TYPE {
+    /* End of sub-messages. */
+    SUB_MSG_PING_END = 0,
+};
+
 enum REDIRECT_FD_TYPE {
     /* Invalid type (useful only internally). */
     REDIRECT_FD_INVALID = -1,
@@ -193,7 +222,8 @@ enum REDIRECT_FD_TYPE {
     REDIRECT_FD_FILE = 0,
     /* Buffer size. (u64) */
     REDIRECT_FD_PIPE_BLOCKING,
-    /* Buffer size. (u64) */
+    /* Buffer size. Once the buffer is full, the oldest data gets dropped
+     * instead of blocking the process. (u64) */
     REDIRECT_FD_PIPE_CYCLIC,
 };
 
@@ -210,6 +240,8 @@ enum GUEST_MSG_TYPE {
     NOTIFY_OUTPUT_AVAILABLE,
     /* ID of process and exit reason (two bytes). (u64 + u8 + u8) */
     NOTIFY_PROCESS_DIED,
+    /* Offset of the data in the output stream and the data. (u64 + BYTES) */
+    RESP_OK_OUTPUT,
 };
 
 #pragma pack(pop)
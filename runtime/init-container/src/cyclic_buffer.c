@@ -3,6 +3,7 @@
 #include <sys/mman.h>
 #include <unistd.h>
 #include <stdio.h>
+#include <string.h>
 
 #include "cyclic_buffer.h"
 
@@ -143,3 +144,42 @@ ssize_t cyclic_buffer_write(int fd, struct cyclic_buffer* cb, size_t count) {
 
     return wrote;
 }
+
+size_t cyclic_buffer_append(struct cyclic_buffer* cb, const char* data, size_t count) {
+    size_t copied = 0;
+    size_t free_space = cyclic_buffer_free_size(cb);
+
+    while (count && free_space) {
+        if (cb->end == cb->buf + cb->size) {
+            cb->end = cb->buf;
+        }
+
+        size_t this_copy_size = min(free_space, min(cb->buf + cb->size - cb->end, count));
+        memcpy(cb->end, data, this_copy_size);
+
+        cb->end += this_copy_size;
+        data += this_copy_size;
+        count -= this_copy_size;
+        copied += this_copy_size;
+        free_space = cyclic_buffer_free_size(cb);
+    }
+
+    return copied;
+}
+
+size_t cyclic_buffer_discard(struct cyclic_buffer* cb, size_t count) {
+    size_t available_data = cyclic_buffer_data_size(cb);
+    if (count >= available_data) {
+        cb->begin = cb->buf;
+        cb->end = cb->buf;
+        return available_data;
+    }
+
+    size_t to_wrap = cb->buf + cb->size - cb->begin;
+    if (count < to_wrap) {
+        cb->begin += count;
+    } else {
+        cb->begin = cb->buf + (count - to_wrap);
+    }
+    return count;
+}
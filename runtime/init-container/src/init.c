@@ -11,6 +11,7 @@
 #include <stdnoreturn.h>
 #include <string.h>
 #include <sys/epoll.h>
+#include <sys/ioctl.h>
 #include <sys/mman.h>
 #include <sys/mount.h>
 #include <sys/reboot.h>
@@ -18,6 +19,7 @@
 #include <sys/stat.h>
 #include <sys/syscall.h>
 #include <sys/sysmacros.h>
+#include <sys/timerfd.h>
 #include <sys/types.h>
 #include <sys/wait.h>
 #include <unistd.h>
@@ -43,6 +45,12 @@
         .path = NULL,               \
     }
 
+/* Host-provided drive backing the root overlay upper layer */
+#define DEV_SCRATCH "/dev/vdb"
+/* Kernel parameter selecting the overlay upper layer: "disk" or "tmpfs:<size MiB>" */
+#define ENV_OVERLAY_UPPER "overlay_upper"
+#define DEFAULT_OVERLAY_TMPFS_MIB 128
+
 #define VPORT_CMD "/dev/vport0p1"
 #define VPORT_NET "/dev/vport0p2"
 #define VPORT_INET "/dev/vport0p3"
@@ -53,6 +61,9 @@
 #define MODE_RW_UGO (S_IRUSR | S_IWUSR | S_IRGRP | S_IWGRP | S_IROTH | S_IWOTH)
 #define OUTPUT_PATH_PREFIX "/var/tmp/guest_agent_private/fds"
 
+/* Time given to the host to read the output of an exited entrypoint. */
+#define ENTRYPOINT_OUTPUT_GRACE_MS 5000
+
 #define NET_MEM_DEFAULT 1048576
 #define NET_MEM_MAX 2097152
 #define MTU_VPN 1220
@@ -74,6 +85,8 @@ enum epoll_fd_type {
     EPOLL_FD_SIG,
     EPOLL_FD_OUT,
     EPOLL_FD_IN,
+    EPOLL_FD_KILL_TIMER,
+    EPOLL_FD_SHUTDOWN_TIMER,
 };
 
 struct epoll_fd_desc {
@@ -81,6 +94,8 @@ struct epoll_fd_desc {
     int fd;
     int src_fd;
     struct redir_fd_desc* data;
+    /* For EPOLL_FD_*_TIMER: ID of the process concerned. */
+    uint64_t proc_id;
 };
 
 extern char** environ;
@@ -154,6 +169,9 @@ int make_cloexec(int fd) {
 }
 */
 
+static int del_epoll_fd_desc(struct epoll_fd_desc* epoll_fd_desc);
+static int add_timer(enum epoll_fd_type type, uint64_t id, uint32_t timeout_ms);
+
 static void cleanup_fd_desc(struct redir_fd_desc* fd_desc) {
     switch (fd_desc->type) {
         case REDIRECT_FD_FILE:
@@ -161,6 +179,11 @@ static void cleanup_fd_desc(struct redir_fd_desc* fd_desc) {
             break;
         case REDIRECT_FD_PIPE_BLOCKING:
         case REDIRECT_FD_PIPE_CYCLIC:
+            /* Input still pending when the process is gone. */
+            if (fd_desc->buffer.epoll_desc) {
+                CHECK(del_epoll_fd_desc(fd_desc->buffer.epoll_desc));
+                fd_desc->buffer.epoll_desc = NULL;
+            }
             if (fd_desc->buffer.fds[0] != -1) {
                 close(fd_desc->buffer.fds[0]);
             }
@@ -177,7 +200,8 @@ static void cleanup_fd_desc(struct redir_fd_desc* fd_desc) {
 
 static bool redir_buffers_empty(struct redir_fd_desc *redirs, size_t len) {
     FILE *f;
-    for (size_t fd = 0; fd < len; ++fd) {
+    /* Skip stdin - data pending there is never queried by the host. */
+    for (size_t fd = 1; fd < len; ++fd) {
         switch (redirs[fd].type) {
             case REDIRECT_FD_FILE:
                 if ((f = fopen(redirs[fd].path, "r")) == 0) {
@@ -217,6 +241,12 @@ struct exit_reason {
     uint8_t type;
 };
 
+static noreturn void shutdown_after_entrypoint(void) {
+    fprintf(stderr, "Shutting down after the entrypoint exit\n");
+    CHECK(kill(-1, SIGKILL));
+    die();
+}
+
 static void send_process_died(uint64_t id, struct exit_reason reason) {
     struct msg_hdr resp = {
         .msg_id = 0,
@@ -292,13 +322,21 @@ static void handle_sigchld(void) {
     send_process_died(proc_desc->id, encode_status(siginfo.ssi_status,
                       siginfo.ssi_code));
 
+    bool buffers_empty = redir_buffers_empty(proc_desc->redirs, 3);
+
     if (proc_desc == g_entrypoint_desc) {
         fprintf(stderr, "Entrypoint exited\n");
-        CHECK(kill(-1, SIGKILL));
-        die();
+        if (buffers_empty) {
+            shutdown_after_entrypoint();
+        }
+        /* Keep running until the host reads the remaining output, see
+         * `handle_query_output`, or the grace period expires. */
+        CHECK(add_timer(EPOLL_FD_SHUTDOWN_TIMER, proc_desc->id,
+                        ENTRYPOINT_OUTPUT_GRACE_MS));
+        return;
     }
 
-    if (redir_buffers_empty(proc_desc->redirs, 3)) {
+    if (buffers_empty) {
         delete_proc(proc_desc);
     }
 }
@@ -484,13 +522,18 @@ static void send_response_u64(msg_id_t msg_id, uint64_t ret_val) {
     CHECK(writen(g_cmds_fd, &ret_val, sizeof(ret_val)));
 }
 
-static void send_response_bytes(msg_id_t msg_id, const char* buf, size_t len) {
-    send_response_hdr(msg_id, RESP_OK_BYTES);
+static void send_response_output(msg_id_t msg_id, uint64_t off,
+                                 const char* buf, size_t len) {
+    send_response_hdr(msg_id, RESP_OK_OUTPUT);
+    CHECK(writen(g_cmds_fd, &off, sizeof(off)));
     CHECK(send_bytes(g_cmds_fd, buf, len));
 }
 
-static void send_response_cyclic_buffer(msg_id_t msg_id, struct cyclic_buffer* cb, size_t len) {
-    send_response_hdr(msg_id, RESP_OK_BYTES);
+static void send_response_output_cyclic_buffer(msg_id_t msg_id, uint64_t off,
+                                               struct cyclic_buffer* cb,
+                                               size_t len) {
+    send_response_hdr(msg_id, RESP_OK_OUTPUT);
+    CHECK(writen(g_cmds_fd, &off, sizeof(off)));
     CHECK(send_bytes_cyclic_buffer(g_cmds_fd, cb, len));
 }
 
@@ -802,6 +845,13 @@ static uint32_t spawn_new_process(struct new_process_args* new_proc_args,
             CHECK(close(proc_desc->redirs[fd].buffer.fds[fd ? 1 : 0]));
             proc_desc->redirs[fd].buffer.fds[fd ? 1 : 0] = -1;
 
+            if (fd == 0) {
+                /* Stdin is registered in epoll only while there is some input
+                 * waiting to be written (see `handle_put_input`). */
+                CHECK(make_nonblocking(proc_desc->redirs[fd].buffer.fds[1]));
+                continue;
+            }
+
             if (add_epoll_fd_desc(&proc_desc->redirs[fd],
                                   proc_desc->redirs[fd].buffer.fds[fd ? 0 : 1],
                                   fd,
@@ -877,6 +927,9 @@ static uint32_t parse_fd_redir(struct redir_fd_desc fd_descs[3]) {
             fd_desc.buffer.cb.buf = MAP_FAILED;
             fd_desc.buffer.fds[0] = -1;
             fd_desc.buffer.fds[1] = -1;
+            fd_desc.buffer.eof = false;
+            fd_desc.buffer.offset = 0;
+            fd_desc.buffer.epoll_desc = NULL;
             break;
         default:
             fprintf(stderr, "Unknown REDIRECT_FD_TYPE: %hhu\n", type);
@@ -999,7 +1052,70 @@ out:
     }
 }
 
-static uint32_t do_kill_process(uint64_t id) {
+static int add_timer(enum epoll_fd_type type, uint64_t id, uint32_t timeout_ms) {
+    struct itimerspec spec = {
+        .it_value = {
+            .tv_sec = timeout_ms / 1000,
+            .tv_nsec = (timeout_ms % 1000) * 1000000L,
+        },
+    };
+    int fd = timerfd_create(CLOCK_MONOTONIC, TFD_NONBLOCK | TFD_CLOEXEC);
+    if (fd < 0) {
+        return -1;
+    }
+    if (timerfd_settime(fd, 0, &spec, NULL) < 0) {
+        goto out_err;
+    }
+
+    struct epoll_fd_desc* epoll_fd_desc = malloc(sizeof(*epoll_fd_desc));
+    if (!epoll_fd_desc) {
+        goto out_err;
+    }
+    epoll_fd_desc->type = type;
+    epoll_fd_desc->fd = fd;
+    epoll_fd_desc->src_fd = -1;
+    epoll_fd_desc->data = NULL;
+    epoll_fd_desc->proc_id = id;
+
+    struct epoll_event event = {
+        .events = EPOLLIN,
+        .data.ptr = epoll_fd_desc,
+    };
+    if (epoll_ctl(g_epoll_fd, EPOLL_CTL_ADD, fd, &event) < 0) {
+        free(epoll_fd_desc);
+        goto out_err;
+    }
+    return 0;
+
+out_err:
+    (void)close(fd);
+    return -1;
+}
+
+static void handle_kill_timer(struct epoll_fd_desc* epoll_fd_desc) {
+    uint64_t id = epoll_fd_desc->proc_id;
+    int fd = epoll_fd_desc->fd;
+
+    CHECK(del_epoll_fd_desc(epoll_fd_desc));
+    CHECK(close(fd));
+
+    /* The process might have exited (and been removed) in the meantime. */
+    struct process_desc* proc_desc = find_process_by_id(id);
+    if (proc_desc && proc_desc->is_alive) {
+        fprintf(stderr, "Grace period of process %llu expired\n", id);
+        if (kill(proc_desc->pid, SIGKILL) < 0) {
+            fprintf(stderr, "Killing process %llu failed: %m\n", id);
+        }
+    }
+}
+
+static noreturn void handle_shutdown_timer(void) {
+    fprintf(stderr, "Entrypoint output was not read in time\n");
+    shutdown_after_entrypoint();
+}
+
+static uint32_t do_kill_process(uint64_t id, uint32_t signal,
+                                uint32_t grace_ms) {
     struct process_desc* proc_desc = find_process_by_id(id);
     if (!proc_desc) {
         return EINVAL;
@@ -1009,10 +1125,16 @@ static uint32_t do_kill_process(uint64_t id) {
         return ESRCH;
     }
 
-    if (kill(proc_desc->pid, SIGKILL) < 0) {
+    if (kill(proc_desc->pid, signal) < 0) {
         return errno;
     }
 
+    if (grace_ms && signal != SIGKILL) {
+        if (add_timer(EPOLL_FD_KILL_TIMER, id, grace_ms) < 0) {
+            return errno;
+        }
+    }
+
     return 0;
 }
 
@@ -1020,6 +1142,8 @@ static void handle_kill_process(msg_id_t msg_id) {
     bool done = false;
     uint32_t ret = 0;
     uint64_t id = 0;
+    uint32_t signal = SIGKILL;
+    uint32_t grace_ms = 0;
 
     while (!done) {
         uint8_t subtype = 0;
@@ -1033,6 +1157,12 @@ static void handle_kill_process(msg_id_t msg_id) {
             case SUB_MSG_KILL_PROCESS_ID:
                 CHECK(recv_u64(g_cmds_fd, &id));
                 break;
+            case SUB_MSG_KILL_PROCESS_SIGNAL:
+                CHECK(recv_u32(g_cmds_fd, &signal));
+                break;
+            case SUB_MSG_KILL_PROCESS_GRACE:
+                CHECK(recv_u32(g_cmds_fd, &grace_ms));
+                break;
             default:
                 fprintf(stderr, "Unknown MSG_KILL_PROCESS subtype: %hhu\n",
                         subtype);
@@ -1040,12 +1170,12 @@ static void handle_kill_process(msg_id_t msg_id) {
         }
     }
 
-    if (!id) {
+    if (!id || signal >= _NSIG) {
         ret = EINVAL;
         goto out;
     }
 
-    ret = do_kill_process(id);
+    ret = do_kill_process(id, signal, grace_ms);
 
 out:
     if (ret) {
@@ -1110,6 +1240,127 @@ out:
     }
 }
 
+static uint32_t do_upload_file(char* path, uint32_t perm, uint32_t uid,
+                               uint32_t gid, uint64_t off, const char* data,
+                               uint64_t len) {
+    uint32_t ret = 0;
+    int fd = -1;
+
+    if (path[0] != '/') {
+        return EINVAL;
+    }
+
+    if (off == 0) {
+        char* last = strrchr(path, '/');
+        if (last != path) {
+            *last = '\0';
+            int r = create_dir_path(path);
+            *last = '/';
+            if (r < 0) {
+                return errno;
+            }
+        }
+        fd = open(path, O_WRONLY | O_CREAT | O_TRUNC | O_CLOEXEC, perm);
+    } else {
+        fd = open(path, O_WRONLY | O_CLOEXEC);
+    }
+    if (fd < 0) {
+        return errno;
+    }
+
+    if (off == 0) {
+        /* `open` honours umask and ignores `perm` for existing files. */
+        if (fchmod(fd, perm) < 0 || fchown(fd, uid, gid) < 0) {
+            ret = errno;
+            goto out;
+        }
+    }
+
+    while (len > 0) {
+        ssize_t n = pwrite(fd, data, len, off);
+        if (n < 0) {
+            if (errno == EINTR) {
+                continue;
+            }
+            ret = errno;
+            goto out;
+        }
+        data += n;
+        len -= n;
+        off += n;
+    }
+
+out:
+    if (close(fd) < 0 && !ret) {
+        ret = errno;
+    }
+    return ret;
+}
+
+static void handle_upload_file(msg_id_t msg_id) {
+    bool done = false;
+    uint32_t ret = 0;
+    char* path = NULL;
+    uint32_t perm = S_IRUSR | S_IWUSR;
+    uint32_t uid = DEFAULT_UID;
+    uint32_t gid = DEFAULT_GID;
+    uint64_t off = 0;
+    char* data = NULL;
+    uint64_t len = 0;
+
+    while (!done) {
+        uint8_t subtype = 0;
+
+        CHECK(recv_u8(g_cmds_fd, &subtype));
+
+        switch (subtype) {
+            case SUB_MSG_UPLOAD_FILE_END:
+                done = true;
+                break;
+            case SUB_MSG_UPLOAD_FILE_PATH:
+                free(path);
+                CHECK(recv_bytes(g_cmds_fd, &path, NULL, /*is_cstring=*/true));
+                break;
+            case SUB_MSG_UPLOAD_FILE_PERM:
+                CHECK(recv_u32(g_cmds_fd, &perm));
+                break;
+            case SUB_MSG_UPLOAD_FILE_USR:
+                CHECK(recv_u32(g_cmds_fd, &uid));
+                break;
+            case SUB_MSG_UPLOAD_FILE_GRP:
+                CHECK(recv_u32(g_cmds_fd, &gid));
+                break;
+            case SUB_MSG_UPLOAD_FILE_DATA:
+                free(data);
+                CHECK(recv_bytes(g_cmds_fd, &data, &len, /*is_cstring=*/false));
+                break;
+            case SUB_MSG_UPLOAD_FILE_OFFSET:
+                CHECK(recv_u64(g_cmds_fd, &off));
+                break;
+            default:
+                fprintf(stderr, "Unknown MSG_UPLOAD_FILE subtype: %hhu\n",
+                        subtype);
+                die();
+        }
+    }
+
+    if (!path) {
+        ret = EINVAL;
+        goto out;
+    }
+
+    ret = do_upload_file(path, perm & 07777, uid, gid, off, data, len);
+
+out:
+    free(data);
+    free(path);
+    if (ret) {
+        send_response_err(msg_id, ret);
+    } else {
+        send_response_ok(msg_id);
+    }
+}
+
 static uint32_t do_query_output_path(char* path, uint64_t off, char** buf_ptr,
                                      uint64_t* len_ptr) {
     uint32_t ret = 0;
@@ -1225,17 +1476,20 @@ static void handle_query_output(msg_id_t msg_id) {
             if (ret) {
                 goto out_err;
             }
-            send_response_bytes(msg_id, buf, len);
+            send_response_output(msg_id, off, buf, len);
             CHECK(munmap(buf, len));
             break;
         case REDIRECT_FD_PIPE_BLOCKING:
-        case REDIRECT_FD_PIPE_CYCLIC:
-            if (off) {
-                ret = EINVAL;
-                goto out_err;
+        case REDIRECT_FD_PIPE_CYCLIC: ;
+            struct cyclic_buffer* cb = &proc_desc->redirs[fd].buffer.cb;
+            uint64_t* offset = &proc_desc->redirs[fd].buffer.offset;
+            bool was_full = cyclic_buffer_free_size(cb) == 0;
+            size_t data_size = cyclic_buffer_data_size(cb);
+            if (len > data_size) {
+                len = data_size;
             }
-            bool was_full = cyclic_buffer_free_size(&proc_desc->redirs[fd].buffer.cb) == 0;
-            send_response_cyclic_buffer(msg_id, &proc_desc->redirs[fd].buffer.cb, len);
+            send_response_output_cyclic_buffer(msg_id, *offset, cb, len);
+            *offset += len;
             if (was_full) {
                 if (add_epoll_fd_desc(&proc_desc->redirs[fd],
                                       proc_desc->redirs[fd].buffer.fds[0],
@@ -1252,6 +1506,9 @@ static void handle_query_output(msg_id_t msg_id) {
     }
 
     if (!proc_desc->is_alive && redir_buffers_empty(proc_desc->redirs, 3)) {
+        if (proc_desc == g_entrypoint_desc) {
+            shutdown_after_entrypoint();
+        }
         delete_proc(proc_desc);
     }
 
@@ -1278,6 +1535,16 @@ static void handle_output_available(struct epoll_fd_desc** epoll_fd_desc_ptr) {
     size_t to_read = cyclic_buffer_free_size(cb);
     bool needs_notification = cyclic_buffer_data_size(cb) == 0;
 
+    if (to_read == 0 && epoll_fd_desc->data->type == REDIRECT_FD_PIPE_CYCLIC) {
+        /* Buffer is full, make room for new output by dropping the oldest. */
+        int available = 0;
+        CHECK(ioctl(epoll_fd_desc->fd, FIONREAD, &available));
+        if (available > 0) {
+            to_read = cyclic_buffer_discard(cb, (size_t)available);
+            epoll_fd_desc->data->buffer.offset += to_read;
+        }
+    }
+
     if (to_read == 0) {
         /* Buffer is full, deregister `epoll_fd_desc` untill it get's emptied. */
         CHECK(del_epoll_fd_desc(epoll_fd_desc));
@@ -1310,6 +1577,181 @@ static void handle_output_available(struct epoll_fd_desc** epoll_fd_desc_ptr) {
     }
 }
 
+static void close_input(struct redir_fd_desc* redir_fd_desc) {
+    CHECK(close(redir_fd_desc->buffer.fds[1]));
+    redir_fd_desc->buffer.fds[1] = -1;
+}
+
+static void handle_input_writable(struct epoll_fd_desc* epoll_fd_desc,
+                                  uint32_t events) {
+    struct redir_fd_desc* redir_fd_desc = epoll_fd_desc->data;
+    struct cyclic_buffer* cb = &redir_fd_desc->buffer.cb;
+
+    if (!(events & EPOLLERR)) {
+        ssize_t ret = cyclic_buffer_write(epoll_fd_desc->fd, cb,
+                                          cyclic_buffer_data_size(cb));
+        if (ret < 0) {
+            if (errno == EPIPE) {
+                events |= EPOLLERR;
+            } else if (errno != EAGAIN) {
+                fprintf(stderr, "Unexpected error while writing in handle_input_writable: %m\n");
+                die();
+            }
+        }
+    }
+
+    if (events & EPOLLERR) {
+        /* The process closed its stdin, pending input will never be read. */
+        cb->begin = cb->buf;
+        cb->end = cb->buf;
+        redir_fd_desc->buffer.eof = true;
+    }
+
+    if (cyclic_buffer_data_size(cb) == 0) {
+        CHECK(del_epoll_fd_desc(epoll_fd_desc));
+        redir_fd_desc->buffer.epoll_desc = NULL;
+        if (redir_fd_desc->buffer.eof) {
+            close_input(redir_fd_desc);
+        }
+    }
+}
+
+static uint32_t do_put_input(uint64_t id, const char* data, uint64_t len) {
+    struct process_desc* proc_desc = find_process_by_id(id);
+    if (!proc_desc || !proc_desc->is_alive) {
+        return ESRCH;
+    }
+
+    struct redir_fd_desc* redir_fd_desc = &proc_desc->redirs[0];
+    if (redir_fd_desc->type != REDIRECT_FD_PIPE_BLOCKING
+            && redir_fd_desc->type != REDIRECT_FD_PIPE_CYCLIC) {
+        return EBADF;
+    }
+    if (redir_fd_desc->buffer.eof) {
+        return EPIPE;
+    }
+
+    struct cyclic_buffer* cb = &redir_fd_desc->buffer.cb;
+    bool was_empty = cyclic_buffer_data_size(cb) == 0;
+
+    if (len == 0) {
+        redir_fd_desc->buffer.eof = true;
+        if (was_empty) {
+            close_input(redir_fd_desc);
+        }
+        return 0;
+    }
+
+    if (len > cyclic_buffer_free_size(cb)) {
+        return EAGAIN;
+    }
+
+    if (was_empty) {
+        if (add_epoll_fd_desc(redir_fd_desc,
+                              redir_fd_desc->buffer.fds[1],
+                              0,
+                              &redir_fd_desc->buffer.epoll_desc) < 0) {
+            if (errno == ENOMEM || errno == ENOSPC) {
+                return errno;
+            } else if (errno != EEXIST) {
+                CHECK(-1);
+            }
+        }
+    }
+
+    (void)cyclic_buffer_append(cb, data, len);
+    return 0;
+}
+
+static void handle_put_input(msg_id_t msg_id) {
+    bool done = false;
+    uint32_t ret = 0;
+    uint64_t id = 0;
+    char* data = NULL;
+    uint64_t len = 0;
+
+    while (!done) {
+        uint8_t subtype = 0;
+        CHECK(recv_u8(g_cmds_fd, &subtype));
+
+        switch (subtype) {
+            case SUB_MSG_PUT_INPUT_END:
+                done = true;
+                break;
+            case SUB_MSG_PUT_INPUT_ID:
+                CHECK(recv_u64(g_cmds_fd, &id));
+                break;
+            case SUB_MSG_PUT_INPUT_DATA:
+                free(data);
+                CHECK(recv_bytes(g_cmds_fd, &data, &len, /*is_cstring=*/false));
+                break;
+            default:
+                fprintf(stderr, "Unknown MSG_PUT_INPUT subtype: %hhu\n",
+                        subtype);
+                die();
+        }
+    }
+
+    if (!id || !data) {
+        ret = EINVAL;
+        goto out;
+    }
+
+    ret = do_put_input(id, data, len);
+
+out:
+    free(data);
+    if (ret) {
+        send_response_err(msg_id, ret);
+    } else {
+        send_response_ok(msg_id);
+    }
+}
+
+static void handle_sync_fs(msg_id_t msg_id) {
+    bool done = false;
+
+    while (!done) {
+        uint8_t subtype = 0;
+
+        CHECK(recv_u8(g_cmds_fd, &subtype));
+
+        switch (subtype) {
+            case SUB_MSG_SYNC_FS_END:
+                done = true;
+                break;
+            default:
+                fprintf(stderr, "Unknown MSG_SYNC_FS subtype: %hhu\n",
+                        subtype);
+                die();
+        }
+    }
+
+    sync();
+    send_response_ok(msg_id);
+}
+
+static void handle_ping(msg_id_t msg_id) {
+    bool done = false;
+
+    while (!done) {
+        uint8_t subtype = 0;
+
+        CHECK(recv_u8(g_cmds_fd, &subtype));
+
+        switch (subtype) {
+            case SUB_MSG_PING_END:
+                done = true;
+                break;
+            default:
+                fprintf(stderr, "Unknown MSG_PING subtype: %hhu\n", subtype);
+                die();
+        }
+    }
+
+    send_response_ok(msg_id);
+}
+
 static void handle_net_ctl(msg_id_t msg_id) {
     bool done = false;
     uint16_t flags = 0;
@@ -1531,12 +1973,22 @@ static void handle_message(void) {
             fprintf(stderr, "MSG_NET_HOST\n");
             handle_net_host(msg_hdr.msg_id);
             break;
-        case MSG_UPLOAD_FILE:
         case MSG_PUT_INPUT:
+            fprintf(stderr, "MSG_PUT_INPUT\n");
+            handle_put_input(msg_hdr.msg_id);
+            break;
+        case MSG_UPLOAD_FILE:
+            fprintf(stderr, "MSG_UPLOAD_FILE\n");
+            handle_upload_file(msg_hdr.msg_id);
+            break;
         case MSG_SYNC_FS:
-            fprintf(stderr, "Not implemented yet!\n");
-            send_response_err(msg_hdr.msg_id, EPROTONOSUPPORT);
-            die();
+            fprintf(stderr, "MSG_SYNC_FS\n");
+            handle_sync_fs(msg_hdr.msg_id);
+            break;
+        case MSG_PING:
+            /* not logged, sent periodically by the host */
+            handle_ping(msg_hdr.msg_id);
+            break;
         default:
             fprintf(stderr, "Unknown message type: %hhu\n", msg_hdr.type);
             send_response_err(msg_hdr.msg_id, ENOPROTOOPT);
@@ -1588,13 +2040,14 @@ static noreturn void main_loop(void) {
             die();
         }
 
+        epoll_fd_desc = event.data.ptr;
+
         if ((event.events & EPOLLERR) && epoll_fd_desc->type != EPOLL_FD_OUT) {
             fprintf(stderr, "Got EPOLLERR on fd: %d, type: %d\n",
                     epoll_fd_desc->fd, epoll_fd_desc->type);
             die();
         }
 
-        epoll_fd_desc = event.data.ptr;
         switch (epoll_fd_desc->type) {
             case EPOLL_FD_CMDS:
                 if (event.events & EPOLLIN) {
@@ -1607,9 +2060,11 @@ static noreturn void main_loop(void) {
                 }
                 break;
             case EPOLL_FD_OUT:
-                /* Need to handle EPOLLOUT and EPOLLERR here. */
-                fprintf(stderr, "EPOLL_FD_OUT is not implemented yet\n");
-                die();
+                if (event.events & (EPOLLOUT | EPOLLERR)) {
+                    assert(epoll_fd_desc->data);
+                    handle_input_writable(epoll_fd_desc, event.events);
+                }
+                break;
             case EPOLL_FD_IN:
                 if (event.events & EPOLLIN) {
                     assert(epoll_fd_desc->data);
@@ -1618,6 +2073,16 @@ static noreturn void main_loop(void) {
                     CHECK(del_epoll_fd_desc(epoll_fd_desc));
                 }
                 break;
+            case EPOLL_FD_KILL_TIMER:
+                if (event.events & EPOLLIN) {
+                    handle_kill_timer(epoll_fd_desc);
+                }
+                break;
+            case EPOLL_FD_SHUTDOWN_TIMER:
+                if (event.events & EPOLLIN) {
+                    handle_shutdown_timer();
+                }
+                break;
             default:
                 fprintf(stderr, "epoll_wait: invalid fd type: %d\n",
                         epoll_fd_desc->type);
@@ -1633,6 +2098,36 @@ static void create_dir(const char *pathname, mode_t mode) {
     }
 }
 
+static void mount_overlay_upper(const char* target) {
+    /* Unrecognized kernel parameters are passed to init as env variables. */
+    const char* upper = getenv(ENV_OVERLAY_UPPER);
+    unsigned long size_mib = DEFAULT_OVERLAY_TMPFS_MIB;
+
+    if (upper && strcmp(upper, "disk") == 0) {
+        /* Size-limited, writes fail with ENOSPC once the drive is full. */
+        CHECK(mount(DEV_SCRATCH, target, "ext4", MS_NOSUID, ""));
+        return;
+    }
+
+    if (upper && strncmp(upper, "tmpfs:", strlen("tmpfs:")) == 0) {
+        char* end = NULL;
+        const char* size = upper + strlen("tmpfs:");
+        errno = 0;
+        size_mib = strtoul(size, &end, 10);
+        if (errno || end == size || *end != '\0' || size_mib == 0) {
+            fprintf(stderr, "Invalid overlay tmpfs size: %s\n", size);
+            die();
+        }
+    } else if (upper && strcmp(upper, "tmpfs") != 0) {
+        fprintf(stderr, "Invalid overlay upper layer: %s\n", upper);
+        die();
+    }
+
+    char opts[64];
+    snprintf(opts, sizeof(opts), "mode=0777,size=%luM", size_mib);
+    CHECK(mount("tmpfs", target, "tmpfs", MS_NOSUID, opts));
+}
+
 int main(void) {
     setbuf(stdin, NULL);
     setbuf(stdout, NULL);
@@ -1646,6 +2141,7 @@ int main(void) {
     load_module("/virtio.ko");
     load_module("/virtio_ring.ko");
     load_module("/virtio_pci.ko");
+    load_module("/virtio_balloon.ko");
     load_module("/net_failover.ko");
     load_module("/virtio_net.ko");
     load_module("/virtio_console.ko");
@@ -1654,6 +2150,11 @@ int main(void) {
     load_module("/virtio_blk.ko");
     load_module("/squashfs.ko");
     load_module("/overlay.ko");
+    load_module("/crc16.ko");
+    load_module("/crc32c_generic.ko");
+    load_module("/mbcache.ko");
+    load_module("/jbd2.ko");
+    load_module("/ext4.ko");
     load_module("/fscache.ko");
     load_module("/af_packet.ko");
     load_module("/ipv6.ko");
@@ -1670,12 +2171,10 @@ int main(void) {
     CHECK(mkdir("/mnt/newroot", DEFAULT_DIR_PERMS));
 
     // 'workdir' and 'upperdir' have to be on the same filesystem
-    CHECK(mount("tmpfs", "/mnt/overlay", "tmpfs",
-                MS_NOSUID,
-                "mode=0777,size=128M"));
+    mount_overlay_upper("/mnt/overlay");
 
-    CHECK(mkdir("/mnt/overlay/upper", S_IRWXU));
-    CHECK(mkdir("/mnt/overlay/work", S_IRWXU));
+    create_dir("/mnt/overlay/upper", S_IRWXU);
+    create_dir("/mnt/overlay/work", S_IRWXU);
 
     CHECK(mount("/dev/vda", "/mnt/image", "squashfs", MS_RDONLY, ""));
     CHECK(mount("overlay", "/mnt/newroot", "overlay", 0,
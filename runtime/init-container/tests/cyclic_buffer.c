@@ -210,6 +210,52 @@ void test_buffer_never_empty(struct test_setup* setup) {
     }
 }
 
+void test_append(struct test_setup* setup) {
+    memset(setup->buf_in, 'a', BUF_SIZE);
+    assert_size_equal(BUF_SIZE / 2, cyclic_buffer_append(&setup->cb, setup->buf_in, BUF_SIZE / 2), "Append");
+    check_cb_invariants(setup, BUF_SIZE / 2);
+    assert_size_equal(BUF_SIZE / 2 - 42, pipe_from_cb(setup, BUF_SIZE / 2 - 42), "Read");
+    check_cb_invariants(setup, 42);
+
+    // appending wraps around the boundary and never overwrites stored data
+    memset(setup->buf_in, 'b', BUF_SIZE);
+    assert_size_equal(BUF_SIZE - 42, cyclic_buffer_append(&setup->cb, setup->buf_in, BUF_SIZE), "Append");
+    check_cb_invariants(setup, BUF_SIZE);
+    assert_size_equal(0, cyclic_buffer_append(&setup->cb, setup->buf_in, BUF_SIZE), "Append");
+    check_cb_invariants(setup, BUF_SIZE);
+
+    assert_size_equal(BUF_SIZE, pipe_from_cb(setup, BUF_SIZE), "Read");
+    check_cb_invariants(setup, 0);
+    memset(setup->buf_in, 'a', 42);
+    assert_buffers_match(setup);
+}
+
+void test_discard(struct test_setup* setup) {
+    memset(setup->buf_in, 'a', BUF_SIZE);
+    assert_size_equal(BUF_SIZE, pipe_to_cb(setup, BUF_SIZE), "Write");
+    assert_size_equal(BUF_SIZE - 42, pipe_from_cb(setup, BUF_SIZE - 42), "Read");
+    check_cb_invariants(setup, 42);
+
+    // stored data wraps around the boundary
+    memset(setup->buf_in, 'b', BUF_SIZE);
+    assert_size_equal(BUF_SIZE - 42, pipe_to_cb(setup, BUF_SIZE - 42), "Write");
+    check_cb_invariants(setup, BUF_SIZE);
+    assert_size_equal(84, cyclic_buffer_discard(&setup->cb, 84), "Discard");
+    check_cb_invariants(setup, BUF_SIZE - 84);
+    memset(setup->buf_out, 0, BUF_SIZE);
+
+    assert_size_equal(BUF_SIZE - 84, pipe_from_cb(setup, BUF_SIZE), "Read");
+    check_cb_invariants(setup, 0);
+    memset(setup->buf_in, 0, BUF_SIZE);
+    memset(setup->buf_in, 'b', BUF_SIZE - 84);
+    assert_buffers_match(setup);
+
+    memset(setup->buf_in, 'c', BUF_SIZE);
+    assert_size_equal(42, pipe_to_cb(setup, 42), "Write");
+    assert_size_equal(42, cyclic_buffer_discard(&setup->cb, BUF_SIZE), "Discard");
+    check_cb_invariants(setup, 0);
+}
+
 int main(void) {
     setbuf(stdin, NULL);
     setbuf(stdout, NULL);
@@ -220,6 +266,8 @@ int main(void) {
     run_test("buffer with some data", test_buffer_with_some_data);
     run_test("buffer never empty, pointer going around the boundary", test_buffer_never_empty);
     run_test("more data in pipe than capacity", test_more_data_in_pipe_than_capacity);
+    run_test("append from memory", test_append);
+    run_test("discard oldest data", test_discard);
 
     puts("Test OK");
     return 0;
@@ -0,0 +1,80 @@
+use std::fs;
+use std::path::Path;
+
+use super::arm::parse_cpuinfo;
+use super::{CpuCache, CpuCacheKind, CpuInfo, CpuTopology};
+use crate::host::count_cpu_list;
+
+const PROC_CPUINFO: &'static str = "/proc/cpuinfo";
+const SYSFS_CPU0: &'static str = "/sys/devices/system/cpu/cpu0";
+
+pub(super) fn cpu_info() -> anyhow::Result<CpuInfo> {
+    let cpuinfo = fs::read_to_string(PROC_CPUINFO)?;
+    let midr = fs::read_to_string(Path::new(SYSFS_CPU0).join("regs/identification/midr_el1"))
+        .ok()
+        .and_then(|midr| u64::from_str_radix(midr.trim().trim_start_matches("0x"), 16).ok());
+    let (model, capabilities) = parse_cpuinfo(&cpuinfo, midr)?;
+
+    Ok(CpuInfo {
+        model,
+        capabilities,
+        topology: read_topology(Path::new(SYSFS_CPU0)),
+    })
+}
+
+fn read_topology(cpu: &Path) -> CpuTopology {
+    let count = |path: &str| {
+        fs::read_to_string(cpu.join(path))
+            .ok()
+            .and_then(|list| count_cpu_list(&list).ok())
+            .map(|count| count as u32)
+    };
+    let threads_per_core = count("topology/thread_siblings_list");
+    let cores_per_socket = count("topology/core_siblings_list")
+        .map(|threads| threads / threads_per_core.unwrap_or(1).max(1));
+
+    let mut caches = Vec::new();
+    for index in fs::read_dir(cpu.join("cache")).into_iter().flatten() {
+        let index = match index {
+            Ok(index) => index.path(),
+            Err(_) => continue,
+        };
+        let read = |name: &str| fs::read_to_string(index.join(name)).ok();
+
+        let level = read("level").and_then(|level| level.trim().parse().ok());
+        let kind = match read("type").as_deref().map(str::trim) {
+            Some("Data") => Some(CpuCacheKind::Data),
+            Some("Instruction") => Some(CpuCacheKind::Instruction),
+            Some("Unified") => Some(CpuCacheKind::Unified),
+            _ => None,
+        };
+        let size = read("size").and_then(|size| parse_size(size.trim()));
+
+        if let (Some(level), Some(kind), Some(size)) = (level, kind, size) {
+            caches.push(CpuCache { level, kind, size });
+        }
+    }
+    caches.sort_by_key(|cache| (cache.level, cache.name()));
+
+    CpuTopology {
+        threads_per_core,
+        cores_per_socket,
+        caches,
+    }
+}
+
+/// Parses sizes in the `48K` sysfs format
+fn parse_size(size: &str) -> Option<u64> {
+    let (value, unit) = match size.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((idx, _)) => size.split_at(idx),
+        None => (size, ""),
+    };
+    let multiplier = match unit {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return None,
+    };
+    value.parse::<u64>().ok().map(|value| value * multiplier)
+}
@@ -0,0 +1,175 @@
+use super::CpuModel;
+
+const IMPLEMENTER_ARM: u8 = 0x41;
+
+/// Parses the first processor entry of `/proc/cpuinfo`. The `MIDR_EL1` register
+/// value is used when the identification fields are missing.
+pub(super) fn parse_cpuinfo(
+    cpuinfo: &str,
+    midr: Option<u64>,
+) -> anyhow::Result<(CpuModel, Vec<String>)> {
+    let field = |name: &str| {
+        cpuinfo
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(key, _)| key.trim() == name)
+            .map(|(_, value)| value.trim())
+    };
+    let number = |name: &str| {
+        field(name).and_then(|value| match value.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => value.parse().ok(),
+        })
+    };
+
+    let midr_bits = |from: u32, len: u32| midr.map(|midr| (midr >> from) & ((1 << len) - 1));
+    let implementer = number("CPU implementer")
+        .or_else(|| midr_bits(24, 8))
+        .ok_or_else(|| anyhow::anyhow!("Unable to read CPU implementer"))?
+        as u8;
+    let variant = number("CPU variant")
+        .or_else(|| midr_bits(20, 4))
+        .unwrap_or(0) as u16;
+    let part = number("CPU part").or_else(|| midr_bits(4, 12)).unwrap_or(0) as u16;
+    let revision = number("CPU revision")
+        .or_else(|| midr_bits(0, 4))
+        .unwrap_or(0) as u8;
+
+    let vendor = implementer_name(implementer)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("Implementer {:#04x}", implementer));
+    let brand = part_name(implementer, part)
+        .map(|part| format!("{} {}", vendor, part))
+        .unwrap_or_else(|| format!("{} Part {:#05x}", vendor, part));
+
+    let capabilities = field("Features")
+        .map(|features| features.split_whitespace().map(str::to_string).collect())
+        .unwrap_or_default();
+
+    let model = CpuModel {
+        brand,
+        vendor,
+        stepping: revision,
+        family: variant,
+        model: part,
+        description: format!(
+            "Variant {} Part {:#05x} Revision {}",
+            variant, part, revision
+        ),
+    };
+    Ok((model, capabilities))
+}
+
+fn implementer_name(implementer: u8) -> Option<&'static str> {
+    Some(match implementer {
+        0x41 => "ARM",
+        0x42 => "Broadcom",
+        0x43 => "Cavium",
+        0x46 => "Fujitsu",
+        0x48 => "HiSilicon",
+        0x4e => "NVIDIA",
+        0x50 => "APM",
+        0x51 => "Qualcomm",
+        0x61 => "Apple",
+        0xc0 => "Ampere",
+        _ => return None,
+    })
+}
+
+fn part_name(implementer: u8, part: u16) -> Option<&'static str> {
+    if implementer != IMPLEMENTER_ARM {
+        return None;
+    }
+    Some(match part {
+        0xd03 => "Cortex-A53",
+        0xd04 => "Cortex-A35",
+        0xd05 => "Cortex-A55",
+        0xd07 => "Cortex-A57",
+        0xd08 => "Cortex-A72",
+        0xd09 => "Cortex-A73",
+        0xd0a => "Cortex-A75",
+        0xd0b => "Cortex-A76",
+        0xd0c => "Neoverse-N1",
+        0xd0d => "Cortex-A77",
+        0xd40 => "Neoverse-V1",
+        0xd41 => "Cortex-A78",
+        0xd49 => "Neoverse-N2",
+        0xd4f => "Neoverse-V2",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRAVITON2: &str = "\
+processor\t: 0
+BogoMIPS\t: 243.75
+Features\t: fp asimd evtstrm aes pmull sha1 sha2 crc32 atomics fphp asimdhp cpuid asimdrdm lrcpc dcpop asimddp ssbs
+CPU implementer\t: 0x41
+CPU architecture: 8
+CPU variant\t: 0x3
+CPU part\t: 0xd0c
+CPU revision\t: 1
+
+processor\t: 1
+BogoMIPS\t: 243.75
+Features\t: fp asimd evtstrm aes pmull sha1 sha2 crc32 atomics fphp asimdhp cpuid asimdrdm lrcpc dcpop asimddp ssbs
+CPU implementer\t: 0x41
+CPU architecture: 8
+CPU variant\t: 0x3
+CPU part\t: 0xd0c
+CPU revision\t: 1
+";
+
+    const AMPERE_ONE: &str = "\
+processor\t: 0
+BogoMIPS\t: 2000.00
+Features\t: fp asimd evtstrm aes pmull sha1 sha2 crc32 atomics fphp asimdhp cpuid asimdrdm jscvt fcma lrcpc dcpop sha3 sm3 sm4 asimddp sha512 asimdfhm dit uscat ilrcpc flagm ssbs sb paca pacg dcpodp flagm2 frint i8mm bf16 rng bti ecv
+CPU implementer\t: 0xc0
+CPU architecture: 8
+CPU variant\t: 0x0
+CPU part\t: 0xac3
+CPU revision\t: 0
+";
+
+    #[test]
+    fn graviton2() {
+        let (model, capabilities) = parse_cpuinfo(GRAVITON2, None).unwrap();
+        assert_eq!(model.vendor, "ARM");
+        assert_eq!(model.brand, "ARM Neoverse-N1");
+        assert_eq!(model.description, "Variant 3 Part 0xd0c Revision 1");
+        assert_eq!((model.family, model.model, model.stepping), (3, 0xd0c, 1));
+        assert_eq!(capabilities.len(), 17);
+        assert!(capabilities.iter().any(|cap| cap == "atomics"));
+    }
+
+    #[test]
+    fn ampere_one() {
+        let (model, capabilities) = parse_cpuinfo(AMPERE_ONE, None).unwrap();
+        assert_eq!(model.vendor, "Ampere");
+        assert_eq!(model.brand, "Ampere Part 0xac3");
+        assert_eq!(model.description, "Variant 0 Part 0xac3 Revision 0");
+        assert!(!capabilities.iter().any(|cap| cap == "sve"));
+        assert!(capabilities.iter().any(|cap| cap == "bf16"));
+    }
+
+    #[test]
+    fn midr_fallback() {
+        let (model, capabilities) = parse_cpuinfo("processor\t: 0\n", Some(0x413fd0c1)).unwrap();
+        assert_eq!(model.brand, "ARM Neoverse-N1");
+        assert_eq!((model.family, model.model, model.stepping), (3, 0xd0c, 1));
+        assert!(capabilities.is_empty());
+    }
+
+    #[test]
+    fn unknown_implementer() {
+        assert!(parse_cpuinfo("processor\t: 0\n", None).is_err());
+
+        let (model, _) =
+            parse_cpuinfo("CPU implementer\t: 0x99\nCPU part\t: 0x001\n", None).unwrap();
+        assert_eq!(model.vendor, "Implementer 0x99");
+        assert_eq!(model.brand, "Implementer 0x99 Part 0x001");
+    }
+}
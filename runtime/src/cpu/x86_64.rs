@@ -0,0 +1,324 @@
+use std::convert::TryFrom;
+
+use raw_cpuid::{CacheType, CpuId, TopologyType};
+
+use super::{CpuCache, CpuCacheKind, CpuInfo, CpuModel, CpuTopology};
+
+pub(super) fn cpu_info() -> anyhow::Result<CpuInfo> {
+    let info = raw_cpuid::CpuId::new();
+    let model = CpuModel::try_from(&info)?;
+    let capabilities = cpu_features(&info)?;
+    let topology = CpuTopology::from(&info);
+
+    Ok(CpuInfo {
+        model,
+        capabilities,
+        topology,
+    })
+}
+
+impl<'a> From<&'a CpuId> for CpuTopology {
+    fn from(info: &'a CpuId) -> Self {
+        let mut topology = CpuTopology::default();
+
+        // logical processor counts are cumulative across levels
+        for level in info.get_extended_topology_info().into_iter().flatten() {
+            match level.level_type() {
+                TopologyType::SMT => topology.threads_per_core = Some(level.processors() as u32),
+                TopologyType::Core => {
+                    let threads = topology.threads_per_core.unwrap_or(1).max(1);
+                    topology.cores_per_socket = Some(level.processors() as u32 / threads);
+                }
+                _ => (),
+            }
+        }
+
+        topology.caches = info
+            .get_cache_parameters()
+            .into_iter()
+            .flatten()
+            .filter_map(|cache| {
+                let kind = match cache.cache_type() {
+                    CacheType::Data => CpuCacheKind::Data,
+                    CacheType::Instruction => CpuCacheKind::Instruction,
+                    CacheType::Unified => CpuCacheKind::Unified,
+                    _ => return None,
+                };
+                let size = cache.associativity()
+                    * cache.physical_line_partitions()
+                    * cache.coherency_line_size()
+                    * cache.sets();
+                Some(CpuCache {
+                    level: cache.level(),
+                    kind,
+                    size: size as u64,
+                })
+            })
+            .collect();
+
+        // AMD reports caches in the extended leaves instead
+        if topology.caches.is_empty() {
+            if let Some(l1) = info.get_l1_cache_and_tlb_info() {
+                topology.caches.push(CpuCache {
+                    level: 1,
+                    kind: CpuCacheKind::Data,
+                    size: l1.dcache_size() as u64 * 1024,
+                });
+                topology.caches.push(CpuCache {
+                    level: 1,
+                    kind: CpuCacheKind::Instruction,
+                    size: l1.icache_size() as u64 * 1024,
+                });
+            }
+            if let Some(l2_l3) = info.get_l2_l3_cache_and_tlb_info() {
+                topology.caches.push(CpuCache {
+                    level: 2,
+                    kind: CpuCacheKind::Unified,
+                    size: l2_l3.l2cache_size() as u64 * 1024,
+                });
+                topology.caches.push(CpuCache {
+                    level: 3,
+                    kind: CpuCacheKind::Unified,
+                    size: l2_l3.l3cache_size() as u64 * 512 * 1024,
+                });
+            }
+            topology.caches.retain(|cache| cache.size > 0);
+        }
+
+        topology
+    }
+}
+
+impl<'a> TryFrom<&'a CpuId> for CpuModel {
+    type Error = anyhow::Error;
+
+    fn try_from(info: &'a CpuId) -> Result<Self, Self::Error> {
+        let brand = info
+            .get_processor_brand_string()
+            .ok_or_else(|| anyhow::anyhow!("Unable to read CPU brand"))?;
+        let vendor = info
+            .get_vendor_info()
+            .ok_or_else(|| anyhow::anyhow!("Unable to read CPU vendor info"))?;
+        let features = info
+            .get_feature_info()
+            .ok_or_else(|| anyhow::anyhow!("Unable to read CPU features"))?;
+
+        let stepping = features.stepping_id();
+        let family = (features.extended_family_id() as u16) + (features.family_id() as u16);
+        let model = ((features.extended_model_id() as u16) << 4) + (features.model_id() as u16);
+
+        Ok(CpuModel {
+            brand: brand.as_str().to_string(),
+            vendor: vendor.to_string(),
+            stepping,
+            family,
+            model,
+            description: format!("Stepping {} Family {} Model {}", stepping, family, model),
+        })
+    }
+}
+
+macro_rules! flags {
+    ($cpu_info:ident, $(($has:ident, $lit:tt)),*) => {{
+        let mut results = Vec::new();
+        $(if ($cpu_info.$has()) {
+            results.push(stringify!($lit).to_lowercase());
+        })*
+        results
+    }}
+}
+
+/// Flags not exposed by `raw_cpuid`, read from register bits
+macro_rules! bits {
+    ($reg:expr, $(($bit:expr, $lit:tt)),*) => {{
+        let mut results = Vec::new();
+        $(if $reg & (1 << $bit) != 0 {
+            results.push(stringify!($lit).to_lowercase());
+        })*
+        results
+    }}
+}
+
+fn cpu_features(info: &CpuId) -> anyhow::Result<Vec<String>> {
+    let features = info
+        .get_feature_info()
+        .ok_or_else(|| anyhow::anyhow!("Unable to read CPU features"))?;
+    let ext_features = info
+        .get_extended_feature_info()
+        .ok_or_else(|| anyhow::anyhow!("Unable to read extended CPU features"))?;
+
+    let mut capabilities = flags!(
+        features,
+        (has_sse3, SSE3),
+        (has_pclmulqdq, PCLMULQDQ),
+        (has_ds_area, DTES64),
+        (has_monitor_mwait, MONITOR),
+        (has_cpl, DSCPL),
+        (has_vmx, VMX),
+        (has_smx, SMX),
+        (has_eist, EIST),
+        (has_tm2, TM2),
+        (has_ssse3, SSSE3),
+        (has_cnxtid, CNXTID),
+        (has_fma, FMA),
+        (has_cmpxchg16b, CMPXCHG16B),
+        (has_pdcm, PDCM),
+        (has_pcid, PCID),
+        (has_dca, DCA),
+        (has_sse41, SSE41),
+        (has_sse42, SSE42),
+        (has_x2apic, X2APIC),
+        (has_movbe, MOVBE),
+        (has_popcnt, POPCNT),
+        (has_tsc_deadline, TSC_DEADLINE),
+        (has_aesni, AESNI),
+        (has_xsave, XSAVE),
+        (has_oxsave, OSXSAVE),
+        (has_avx, AVX),
+        (has_f16c, F16C),
+        (has_rdrand, RDRAND),
+        (has_hypervisor, HYPERVISOR),
+        (has_fpu, FPU),
+        (has_vme, VME),
+        (has_de, DE),
+        (has_pse, PSE),
+        (has_tsc, TSC),
+        (has_msr, MSR),
+        (has_pae, PAE),
+        (has_mce, MCE),
+        (has_cmpxchg8b, CX8),
+        (has_apic, APIC),
+        (has_sysenter_sysexit, SEP),
+        (has_mtrr, MTRR),
+        (has_pge, PGE),
+        (has_mca, MCA),
+        (has_cmov, CMOV),
+        (has_pat, PAT),
+        (has_pse36, PSE36),
+        (has_psn, PSN),
+        (has_clflush, CLFSH),
+        (has_ds, DS),
+        (has_acpi, ACPI),
+        (has_mmx, MMX),
+        (has_fxsave_fxstor, FXSR),
+        (has_sse, SSE),
+        (has_sse2, SSE2),
+        (has_ss, SS),
+        (has_htt, HTT),
+        (has_tm, TM),
+        (has_pbe, PBE)
+    );
+    capabilities.extend(
+        flags!(
+            ext_features,
+            (has_fsgsbase, FSGSBASE),
+            (has_tsc_adjust_msr, ADJUST_MSR),
+            (has_bmi1, BMI1),
+            (has_hle, HLE),
+            (has_avx2, AVX2),
+            (has_fdp, FDP),
+            (has_smep, SMEP),
+            (has_bmi2, BMI2),
+            (has_rep_movsb_stosb, REP_MOVSB_STOSB),
+            (has_invpcid, INVPCID),
+            (has_rtm, RTM),
+            (has_rdtm, RDTM),
+            (has_fpu_cs_ds_deprecated, DEPRECATE_FPU_CS_DS),
+            (has_mpx, MPX),
+            (has_rdta, RDTA),
+            (has_rdseed, RDSEED),
+            (has_adx, ADX),
+            (has_smap, SMAP),
+            (has_clflushopt, CLFLUSHOPT),
+            (has_processor_trace, PROCESSOR_TRACE),
+            (has_sha, SHA),
+            (has_sgx, SGX),
+            (has_avx512f, AVX512F),
+            (has_avx512dq, AVX512DQ),
+            (has_avx512_ifma, AVX512_IFMA),
+            (has_avx512pf, AVX512PF),
+            (has_avx512er, AVX512ER),
+            (has_avx512cd, AVX512CD),
+            (has_avx512bw, AVX512BW),
+            (has_avx512vl, AVX512VL),
+            (has_clwb, CLWB),
+            (has_prefetchwt1, PREFETCHWT1),
+            (has_umip, UMIP),
+            (has_pku, PKU),
+            (has_ospke, OSPKE),
+            (has_rdpid, RDPID),
+            (has_sgx_lc, SGX_LC)
+        )
+        .into_iter(),
+    );
+
+    // leaf 7, subleaf 0
+    let leaf7 = raw_cpuid::cpuid!(7, 0);
+    capabilities.extend(bits!(
+        leaf7.ecx,
+        (1, AVX512_VBMI),
+        (5, WAITPKG),
+        (6, AVX512_VBMI2),
+        (8, GFNI),
+        (9, VAES),
+        (10, VPCLMULQDQ),
+        (11, AVX512_VNNI),
+        (12, AVX512_BITALG),
+        (14, AVX512_VPOPCNTDQ),
+        (25, CLDEMOTE),
+        (27, MOVDIRI),
+        (28, MOVDIR64B)
+    ));
+    capabilities.extend(bits!(
+        leaf7.edx,
+        (2, AVX512_4VNNIW),
+        (3, AVX512_4FMAPS),
+        (4, FSRM),
+        (8, AVX512_VP2INTERSECT),
+        (10, MD_CLEAR),
+        (14, SERIALIZE),
+        (16, TSXLDTRK),
+        (18, PCONFIG),
+        (20, CET_IBT),
+        (22, AMX_BF16),
+        (23, AVX512_FP16),
+        (24, AMX_TILE),
+        (25, AMX_INT8),
+        (26, SPEC_CTRL),
+        (27, STIBP),
+        (28, FLUSH_L1D),
+        (29, ARCH_CAPABILITIES),
+        (31, SSBD)
+    ));
+    // leaf 7, subleaf 1 (EAX of subleaf 0 holds the max subleaf)
+    if leaf7.eax >= 1 {
+        let leaf7_1 = raw_cpuid::cpuid!(7, 1);
+        capabilities.extend(bits!(leaf7_1.eax, (4, AVX_VNNI), (5, AVX512_BF16)));
+    }
+
+    if let Some(amd_features) = info.get_extended_processor_and_feature_identifiers() {
+        capabilities.extend(flags!(
+            amd_features,
+            (has_lahf_sahf, LAHF_LM),
+            (has_svm, SVM),
+            (has_lzcnt, ABM),
+            (has_sse4a, SSE4A),
+            (has_misaligned_sse_mode, MISALIGNSSE),
+            (has_prefetchw, PREFETCHW),
+            (has_xop, XOP),
+            (has_skinit, SKINIT),
+            (has_fma4, FMA4),
+            (has_tbm, TBM),
+            (has_topology_extensions, TOPOEXT),
+            (has_monitorx_mwaitx, MWAITX),
+            (has_syscall_sysret, SYSCALL),
+            (has_execute_disable, NX),
+            (has_mmx_extensions, MMXEXT),
+            (has_1gib_pages, PDPE1GB),
+            (has_rdtscp, RDTSCP),
+            (has_64bit_mode, LM)
+        ));
+    }
+
+    Ok(capabilities)
+}
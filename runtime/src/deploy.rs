@@ -1,16 +1,67 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::io::SeekFrom;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::str::FromStr;
 
 use bollard_stubs::models::ContainerConfig;
 use crc::crc32;
 use serde::{Deserialize, Serialize};
+use tokio::fs;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tokio::process::Command;
 use tokio_byteorder::LittleEndian;
 use uuid::Uuid;
 
 use ya_runtime_sdk::runtime_api::deploy::ContainerVolume;
 
+/// Writable scratch disk backing the guest root filesystem overlay
+const FILE_STORAGE: &'static str = "storage.img";
+const DEFAULT_OVERLAY_TMPFS_MIB: usize = 128;
+
+/// Backing of the upper (writable) layer of the guest root filesystem overlay
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverlayUpper {
+    /// In-memory filesystem of the given size
+    Tmpfs { size_mib: usize },
+    /// Host-backed scratch disk of `Deployment::storage_mib` size; the default,
+    /// as for the `--overlay` command line option
+    #[default]
+    Disk,
+}
+
+/// Parses `disk` and `tmpfs[:<size MiB>]`
+impl FromStr for OverlayUpper {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut split = s.splitn(2, ':');
+        match (split.next(), split.next()) {
+            (Some("disk"), None) => Ok(OverlayUpper::Disk),
+            (Some("tmpfs"), None) => Ok(OverlayUpper::Tmpfs {
+                size_mib: DEFAULT_OVERLAY_TMPFS_MIB,
+            }),
+            (Some("tmpfs"), Some(size)) => match size.parse() {
+                Ok(size_mib) if size_mib > 0 => Ok(OverlayUpper::Tmpfs { size_mib }),
+                _ => Err(anyhow::anyhow!("Invalid tmpfs size: {}", size)),
+            },
+            _ => Err(anyhow::anyhow!("Invalid overlay upper layer: {}", s)),
+        }
+    }
+}
+
+/// Formats the value of the `overlay_upper` guest kernel parameter
+impl fmt::Display for OverlayUpper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverlayUpper::Tmpfs { size_mib } => write!(f, "tmpfs:{}", size_mib),
+            OverlayUpper::Disk => write!(f, "disk"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Deployment {
     #[serde(default)]
@@ -18,17 +69,30 @@ pub struct Deployment {
     #[serde(default)]
     pub mem_mib: usize,
     #[serde(default)]
+    pub storage_mib: usize,
+    /// Deployments saved before the option was introduced have no storage
+    #[serde(default = "legacy_overlay")]
+    pub overlay: OverlayUpper,
+    #[serde(default)]
     pub task_package: PathBuf,
     pub user: (u32, u32),
     pub volumes: Vec<ContainerVolume>,
     pub config: ContainerConfig,
 }
 
+fn legacy_overlay() -> OverlayUpper {
+    OverlayUpper::Tmpfs {
+        size_mib: DEFAULT_OVERLAY_TMPFS_MIB,
+    }
+}
+
 impl Deployment {
     pub async fn try_from_input<Input>(
         mut input: Input,
         cpu_cores: usize,
         mem_mib: usize,
+        storage_mib: usize,
+        overlay: OverlayUpper,
         task_package: PathBuf,
     ) -> Result<Self, anyhow::Error>
     where
@@ -57,10 +121,16 @@ impl Deployment {
             return Err(anyhow::anyhow!("Invalid ContainerConfig crc32 sum"));
         }
 
+        if overlay == OverlayUpper::Disk && storage_mib == 0 {
+            return Err(anyhow::anyhow!("Disk-backed overlay requires storage"));
+        }
+
         let config: ContainerConfig = serde_json::from_str(&json)?;
         Ok(Deployment {
             cpu_cores,
             mem_mib,
+            storage_mib,
+            overlay,
             task_package,
             user: parse_user(config.user.as_ref()).unwrap_or((0, 0)),
             volumes: parse_volumes(config.volumes.as_ref()),
@@ -75,12 +145,55 @@ impl Deployment {
             .map(|v| v.iter().map(|s| s.as_str()).collect())
             .unwrap_or_else(Vec::new)
     }
+
+    /// Path to the scratch disk, if the deployment is provided with one
+    pub fn storage(&self, work_dir: &Path) -> Option<PathBuf> {
+        (self.overlay == OverlayUpper::Disk && self.storage_mib > 0)
+            .then(|| work_dir.join(FILE_STORAGE))
+    }
+
+    /// Creates a sparse, ext4-formatted scratch disk of `storage_mib` size
+    pub async fn create_storage(&self, work_dir: &Path) -> anyhow::Result<()> {
+        let path = match self.storage(work_dir) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .await?
+            .set_len(self.storage_mib as u64 * 1024 * 1024)
+            .await?;
+
+        let output = Command::new("mkfs.ext4")
+            .args(&["-q", "-F", "-m", "0"])
+            .arg(&path)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| anyhow::anyhow!("Unable to execute mkfs.ext4: {}", e))?;
+
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "Unable to format the storage disk: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
 }
 
 fn parse_user(user: Option<&String>) -> anyhow::Result<(u32, u32)> {
-    let user = user
-        .map(|s| s.trim())
-        .ok_or_else(|| anyhow::anyhow!("User field missing"))?;
+    let user = user.ok_or_else(|| anyhow::anyhow!("User field missing"))?;
+    parse_user_spec(user)
+}
+
+/// Parses a `<uid>:<gid>` pair
+pub fn parse_user_spec(user: &str) -> anyhow::Result<(u32, u32)> {
+    let user = user.trim();
     let mut split = user.splitn(2, ":");
     let uid: u32 = split
         .next()
@@ -93,6 +206,17 @@ fn parse_user(user: Option<&String>) -> anyhow::Result<(u32, u32)> {
     Ok((uid, gid))
 }
 
+/// Applies `overrides` on top of `env`, replacing variables of the same name
+pub fn merge_env(env: Vec<&str>, overrides: &[String]) -> Vec<String> {
+    let name = |var: &str| var.split('=').next().unwrap_or_default().to_string();
+    let mut env = env.into_iter().map(String::from).collect::<Vec<_>>();
+    for var in overrides {
+        env.retain(|v| name(v) != name(var));
+        env.push(var.clone());
+    }
+    env
+}
+
 fn parse_volumes(volumes: Option<&HashMap<String, HashMap<(), ()>>>) -> Vec<ContainerVolume> {
     let volumes = match volumes {
         Some(v) => v,
@@ -106,3 +230,84 @@ fn parse_volumes(volumes: Option<&HashMap<String, HashMap<(), ()>>>) -> Vec<Cont
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserialize_legacy_deployment() {
+        let json = r#"{
+            "cpu_cores": 1,
+            "mem_mib": 128,
+            "task_package": "/tmp/task.gvmi",
+            "user": [0, 0],
+            "volumes": [],
+            "config": {}
+        }"#;
+        let deployment: Deployment = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            deployment.overlay,
+            OverlayUpper::Tmpfs {
+                size_mib: DEFAULT_OVERLAY_TMPFS_MIB
+            }
+        );
+        assert_eq!(deployment.storage_mib, 0);
+        assert!(deployment.storage(Path::new("/tmp")).is_none());
+    }
+
+    #[test]
+    fn merge_env_overrides() {
+        let env = vec!["PATH=/bin", "HOME=/root", "LANG=C"];
+        let overrides = ["HOME=/home/user".to_string(), "DEBUG=1".to_string()];
+        assert_eq!(
+            merge_env(env, &overrides),
+            vec!["PATH=/bin", "LANG=C", "HOME=/home/user", "DEBUG=1"]
+        );
+    }
+
+    #[test]
+    fn merge_env_last_override_wins() {
+        let overrides = ["A=1".to_string(), "A=2".to_string()];
+        assert_eq!(merge_env(vec!["A=0"], &overrides), vec!["A=2"]);
+    }
+
+    #[test]
+    fn merge_env_without_value() {
+        // images may declare variables without a value
+        let overrides = ["KEY=value".to_string()];
+        assert_eq!(
+            merge_env(vec!["KEY", "KEY2"], &overrides),
+            vec!["KEY2", "KEY=value"]
+        );
+        // a name is not a prefix of a longer one
+        let overrides = ["KEY=".to_string()];
+        assert_eq!(
+            merge_env(vec!["KEY_2=1"], &overrides),
+            vec!["KEY_2=1", "KEY="]
+        );
+    }
+
+    #[test]
+    fn parse_numeric_user_spec() {
+        assert_eq!(parse_user_spec("1000:100").unwrap(), (1000, 100));
+        assert_eq!(parse_user_spec(" 0:0\n").unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn parse_invalid_user_spec() {
+        // user and group names are not resolved
+        for user in [
+            "root",
+            "root:root",
+            "1000:users",
+            "1000",
+            "1000:",
+            ":100",
+            "-1:0",
+            "",
+        ] {
+            assert!(parse_user_spec(user).is_err(), "{}", user);
+        }
+    }
+}
@@ -1,48 +1,280 @@
-use std::process::Command;
-
-pub fn detect_pci(pci_id: String, vendor_id: String) -> String {
-
-	let mut device_found = false;
-	let mut device_name = "None";
-
-	let cmd = Command::new("lspci")
-		.arg("-vnn")
-		.output()
-		.expect("failed to execute process");
-
-	let stdout = String::from_utf8_lossy(&cmd.stdout);
-	let lines = stdout.split("\n");
-
-	for line in lines {
-		if line.starts_with(&pci_id) {
-			let index_vid = line[..].find(&vendor_id);
-			if !index_vid.is_none()
-			{
-				device_found = true;
-				let index_start_vid = index_vid.unwrap();
-				let index_start_pid = index_start_vid + line[index_start_vid..].find(":").unwrap()+1;
-				let index_end_pid = index_start_pid + line[index_start_pid..].find("]").unwrap();
-				let s_pid = &line[index_start_pid..index_end_pid];
-				let vid = u16::from_str_radix(&vendor_id, 16).unwrap();
-				let pid = u16::from_str_radix(s_pid, 16).unwrap();
-				let device = pci_ids::Device::from_vid_pid(vid, pid).unwrap();
-				device_name = pci_ids::Device::name(device);
-			}
-			else {
-				break;
-			}			
-		}
-		else if device_found {
-			if !line.find("Kernel driver in use").is_none() {
-				let line_driver: Vec<&str> = line.split(":").collect();
-				let driver = line_driver[1].trim_start();
-				if driver != "vfio-pci" {
-					device_name = "None";
-				}
-				break;
-			}
-		}
-	}
-
-	device_name.to_string()
-}
\ No newline at end of file
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Context;
+
+/// Default location of PCI devices in sysfs
+pub const SYSFS_PCI_DEVICES: &'static str = "/sys/bus/pci/devices";
+
+const PCI_BAR_COUNT: usize = 6;
+const IORESOURCE_MEM: u64 = 0x200;
+const IORESOURCE_PREFETCH: u64 = 0x2000;
+
+/// PCI device address, `[<domain>:]<bus>:<device>.<function>`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub domain: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl FromStr for PciAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow::anyhow!("Invalid PCI address: {}", s);
+
+        let mut parts = s.trim().rsplitn(3, ':');
+        let slot = parts.next().ok_or_else(invalid)?;
+        let bus = parts.next().ok_or_else(invalid)?;
+        let domain = parts.next().unwrap_or("0000");
+        let (device, function) = slot.split_once('.').ok_or_else(invalid)?;
+
+        let address = PciAddress {
+            domain: u16::from_str_radix(domain, 16).map_err(|_| invalid())?,
+            bus: u8::from_str_radix(bus, 16).map_err(|_| invalid())?,
+            device: u8::from_str_radix(device, 16).map_err(|_| invalid())?,
+            function: u8::from_str_radix(function, 16).map_err(|_| invalid())?,
+        };
+        if address.device > 0x1f || address.function > 0x7 {
+            return Err(invalid());
+        }
+        Ok(address)
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{:x}",
+            self.domain, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// PCI device as described by sysfs
+#[derive(Clone, Debug)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// Base class, subclass and programming interface
+    pub class: u32,
+    /// Name of the bound kernel driver
+    pub driver: Option<String>,
+    pub iommu_group: Option<u32>,
+    pub bars: Vec<PciBar>,
+}
+
+/// PCI base address register
+#[derive(Clone, Debug)]
+pub struct PciBar {
+    pub size: u64,
+    /// Prefetchable memory, e.g. device RAM
+    pub prefetchable: bool,
+}
+
+impl PciDevice {
+    /// Reads the device at `address` from a sysfs PCI devices directory
+    pub fn read(sysfs: &Path, address: PciAddress) -> anyhow::Result<Self> {
+        let path = sysfs.join(address.to_string());
+        if !path.exists() {
+            anyhow::bail!("PCI device {} not found", address);
+        }
+
+        Ok(PciDevice {
+            address,
+            vendor_id: read_hex(&path.join("vendor"))? as u16,
+            device_id: read_hex(&path.join("device"))? as u16,
+            class: read_hex(&path.join("class"))?,
+            driver: link_name(&path.join("driver")),
+            iommu_group: link_name(&path.join("iommu_group")).and_then(|s| s.parse().ok()),
+            bars: read_bars(&path.join("resource"))?,
+        })
+    }
+
+    /// Device model name from the PCI ID database
+    pub fn name(&self) -> Option<&'static str> {
+        pci_ids::Device::from_vid_pid(self.vendor_id, self.device_id).map(|d| d.name())
+    }
+
+    /// Vendor name from the PCI ID database
+    pub fn vendor_name(&self) -> Option<&'static str> {
+        pci_ids::Vendor::from_id(self.vendor_id).map(|v| v.name())
+    }
+
+    /// Whether the device is a display controller
+    pub fn is_display(&self) -> bool {
+        self.class >> 16 == 0x03
+    }
+}
+
+fn read_hex(path: &Path) -> anyhow::Result<u32> {
+    let value =
+        fs::read_to_string(path).with_context(|| format!("Unable to read {}", path.display()))?;
+    let value = value.trim();
+    u32::from_str_radix(value.trim_start_matches("0x"), 16)
+        .with_context(|| format!("Invalid value in {}: {}", path.display(), value))
+}
+
+/// Parses `<start> <end> <flags>` lines of a sysfs `resource` file
+fn read_bars(path: &Path) -> anyhow::Result<Vec<PciBar>> {
+    let resources =
+        fs::read_to_string(path).with_context(|| format!("Unable to read {}", path.display()))?;
+    let parse = |value: &str| {
+        u64::from_str_radix(value.trim_start_matches("0x"), 16)
+            .with_context(|| format!("Invalid value in {}: {}", path.display(), value))
+    };
+
+    let mut bars = Vec::new();
+    for line in resources.lines().take(PCI_BAR_COUNT) {
+        let values = line
+            .split_whitespace()
+            .map(parse)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let (start, end, flags) = match values.as_slice() {
+            [start, end, flags] => (*start, *end, *flags),
+            _ => anyhow::bail!("Invalid resource in {}: {}", path.display(), line),
+        };
+        if end == 0 || flags & IORESOURCE_MEM == 0 {
+            continue;
+        }
+        bars.push(PciBar {
+            size: end - start + 1,
+            prefetchable: flags & IORESOURCE_PREFETCH != 0,
+        });
+    }
+    Ok(bars)
+}
+
+fn link_name(path: &Path) -> Option<String> {
+    fs::read_link(path)
+        .ok()?
+        .file_name()
+        .map(|s| s.to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    const GPU_RESOURCE: &str = "\
+0x00000000fb000000 0x00000000fbffffff 0x0000000000040200
+0x000000e000000000 0x000000efffffffff 0x000000000014220c
+0x0000000000000000 0x0000000000000000 0x0000000000000000
+0x000000f000000000 0x000000f001ffffff 0x000000000014220c
+0x0000000000000000 0x0000000000000000 0x0000000000000000
+0x000000000000e000 0x000000000000e07f 0x0000000000040101
+0x00000000fc000000 0x00000000fc07ffff 0x0000000000046200
+";
+
+    const AUDIO_RESOURCE: &str = "\
+0x00000000fe010000 0x00000000fe013fff 0x0000000000140204
+0x0000000000000000 0x0000000000000000 0x0000000000000000
+";
+
+    fn fake_device(
+        sysfs: &Path,
+        address: &str,
+        ids: [&str; 3],
+        resource: &str,
+        driver: Option<&str>,
+        iommu_group: Option<u32>,
+    ) {
+        let path = sysfs.join(address);
+        fs::create_dir(&path).unwrap();
+        for (name, id) in ["vendor", "device", "class"].iter().zip(ids.iter()) {
+            fs::write(path.join(name), format!("{}\n", id)).unwrap();
+        }
+        fs::write(path.join("resource"), resource).unwrap();
+        if let Some(driver) = driver {
+            symlink(
+                format!("../../../bus/pci/drivers/{}", driver),
+                path.join("driver"),
+            )
+            .unwrap();
+        }
+        if let Some(group) = iommu_group {
+            symlink(
+                format!("../../../kernel/iommu_groups/{}", group),
+                path.join("iommu_group"),
+            )
+            .unwrap();
+        }
+    }
+
+    fn fake_sysfs() -> TempDir {
+        let dir = TempDir::new("sysfs").unwrap();
+        fake_device(
+            dir.path(),
+            "0000:01:00.0",
+            ["0x10de", "0x2204", "0x030000"],
+            GPU_RESOURCE,
+            Some("vfio-pci"),
+            Some(12),
+        );
+        fake_device(
+            dir.path(),
+            "0000:00:1f.3",
+            ["0x8086", "0xa348", "0x040300"],
+            AUDIO_RESOURCE,
+            None,
+            None,
+        );
+        dir
+    }
+
+    #[test]
+    fn read_device() {
+        let sysfs = fake_sysfs();
+        let address = "01:00.0".parse().unwrap();
+        let device = PciDevice::read(sysfs.path(), address).unwrap();
+
+        assert_eq!(device.address, address);
+        assert_eq!(device.vendor_id, 0x10de);
+        assert_eq!(device.device_id, 0x2204);
+        assert_eq!(device.class, 0x030000);
+        assert!(device.is_display());
+        assert_eq!(device.driver.as_deref(), Some("vfio-pci"));
+        assert_eq!(device.iommu_group, Some(12));
+
+        // I/O ports and the expansion ROM are not listed
+        let bars = device
+            .bars
+            .iter()
+            .map(|bar| (bar.size, bar.prefetchable))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            bars,
+            vec![(16u64 << 20, false), (64 << 30, true), (32 << 20, true)]
+        );
+    }
+
+    #[test]
+    fn read_unbound_device() {
+        let sysfs = fake_sysfs();
+        let address = "0000:00:1f.3".parse().unwrap();
+        let device = PciDevice::read(sysfs.path(), address).unwrap();
+
+        assert!(!device.is_display());
+        assert_eq!(device.driver, None);
+        assert_eq!(device.iommu_group, None);
+        assert_eq!(device.bars.len(), 1);
+        assert_eq!(device.bars[0].size, 16 << 10);
+    }
+
+    #[test]
+    fn read_missing_device() {
+        let sysfs = fake_sysfs();
+        let address = "0000:02:00.0".parse().unwrap();
+        assert!(PciDevice::read(sysfs.path(), address).is_err());
+    }
+}
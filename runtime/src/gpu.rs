@@ -1,29 +1,78 @@
-use crate::detect_pci;
+use std::path::Path;
+
+use crate::detect_pci::{PciAddress, PciDevice, SYSFS_PCI_DEVICES};
+
+const DRIVER_VFIO_PCI: &'static str = "vfio-pci";
+const PCI_VENDOR_NVIDIA: u16 = 0x10de;
 
 pub struct GpuInfo {
+    pub device: PciDevice,
     pub name: String,
+    pub vendor: String,
+    /// Size of the largest prefetchable memory BAR, which maps the device RAM
+    pub memory: u64,
 }
 
 impl GpuInfo {
-    pub fn try_new() -> anyhow::Result<GpuInfo> {
-
-        let mut gpu_name = String::from("None");
-        let nvidia_vendor_id = String::from("10de");
-
-        match std::env::var("GPU_PCI") {
-            Ok(val) => {
-                if val != "no" {
-                    let gpu_pci_id = String::from(val);
-                    gpu_name = detect_pci::detect_pci(gpu_pci_id, nvidia_vendor_id);
-                }
-            },
-            Err(_e) => {}
+    pub fn try_new(address: PciAddress) -> anyhow::Result<GpuInfo> {
+        let device = PciDevice::read(Path::new(SYSFS_PCI_DEVICES), address)?;
+        if !device.is_display() {
+            anyhow::bail!(
+                "PCI device {} is not a display controller (class {:06x})",
+                address,
+                device.class
+            );
         }
+        let name = match device.name() {
+            Some(name) => name.to_string(),
+            None => format!("{:04x}:{:04x}", device.vendor_id, device.device_id),
+        };
+        let vendor = match device.vendor_name() {
+            Some(vendor) => vendor.to_string(),
+            None => format!("{:04x}", device.vendor_id),
+        };
+        let memory = device
+            .bars
+            .iter()
+            .filter(|bar| bar.prefetchable)
+            .map(|bar| bar.size)
+            .max()
+            .unwrap_or_default();
 
         Ok(GpuInfo {
-            name: gpu_name.to_string(),
+            device,
+            name,
+            vendor,
+            memory,
         })
+    }
+
+    /// Whether the device is a CUDA capable NVIDIA GPU
+    pub fn is_nvidia(&self) -> bool {
+        self.device.vendor_id == PCI_VENDOR_NVIDIA
+    }
 
+    /// Whether the device is bound to `vfio-pci` and can be passed through
+    pub fn is_vfio_bound(&self) -> bool {
+        self.device.driver.as_deref() == Some(DRIVER_VFIO_PCI)
     }
 }
 
+/// Verifies that the device can be passed through to the VM, i.e. that it belongs
+/// to an IOMMU group and is bound to the `vfio-pci` driver
+pub fn check_passthrough(address: PciAddress) -> anyhow::Result<PciDevice> {
+    let device = PciDevice::read(Path::new(SYSFS_PCI_DEVICES), address)?;
+    if device.iommu_group.is_none() {
+        anyhow::bail!("PCI device {} is not assigned to an IOMMU group", address);
+    }
+    match device.driver.as_deref() {
+        Some(DRIVER_VFIO_PCI) => Ok(device),
+        Some(driver) => anyhow::bail!(
+            "PCI device {} is bound to {} instead of {}",
+            address,
+            driver,
+            DRIVER_VFIO_PCI
+        ),
+        None => anyhow::bail!("PCI device {} is not bound to {}", address, DRIVER_VFIO_PCI),
+    }
+}
@@ -1,18 +1,26 @@
 use futures::channel::mpsc;
 use futures::future::{BoxFuture, FutureExt};
 use futures::lock::Mutex;
-use futures::{SinkExt, StreamExt};
+use futures::StreamExt;
+use std::convert::TryFrom;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 use std::{io, marker::PhantomData};
 use tokio::{
-    io::{split, AsyncWriteExt, ReadHalf, WriteHalf},
+    io::{split, AsyncRead, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
     net::UnixStream,
-    spawn, time,
+    spawn,
+    task::JoinHandle,
+    time,
 };
 
-pub use crate::response_parser::Notification;
+pub use crate::guest_agent_error::{errno_name, GuestAgentError};
+use crate::pending::PendingRequests;
 use crate::response_parser::{parse_one_response, GuestAgentMessage, Response, ResponseWithId};
+pub use crate::response_parser::{Notification, OutputChunk};
 
 #[repr(u8)]
 enum MsgType {
@@ -20,15 +28,13 @@ enum MsgType {
     MsgRunProcess,
     MsgKillProcess,
     MsgMountVolume,
-    #[allow(unused)]
     MsgUploadFile,
     MsgQueryOutput,
-    #[allow(unused)]
     MsgPutInput,
-    #[allow(unused)]
     MsgSyncFs,
     MsgNetCtl,
     MsgNetHost,
+    MsgPing,
 }
 
 enum SubMsgQuitType {
@@ -50,6 +56,8 @@ enum SubMsgRunProcessType<'a> {
 enum SubMsgKillProcessType {
     SubMsgEnd,
     SubMsgKillProcessId(u64),
+    SubMsgKillProcessSignal(u32),
+    SubMsgKillProcessGrace(u32),
 }
 
 enum SubMsgMountVolumeType<'a> {
@@ -58,6 +66,16 @@ enum SubMsgMountVolumeType<'a> {
     SubMsgMountVolumePath(&'a [u8]),
 }
 
+enum SubMsgUploadFileType<'a> {
+    SubMsgEnd,
+    SubMsgUploadFilePath(&'a [u8]),
+    SubMsgUploadFilePerm(u32),
+    SubMsgUploadFileUsr(u32),
+    SubMsgUploadFileGrp(u32),
+    SubMsgUploadFileData(&'a [u8]),
+    SubMsgUploadFileOffset(u64),
+}
+
 enum SubMsgQueryOutputType {
     SubMsgEnd,
     SubMsgQueryOutputId(u64),
@@ -66,6 +84,16 @@ enum SubMsgQueryOutputType {
     SubMsgQueryOutputLen(u64),
 }
 
+enum SubMsgPutInputType<'a> {
+    SubMsgEnd,
+    SubMsgPutInputId(u64),
+    SubMsgPutInputData(&'a [u8]),
+}
+
+enum SubMsgSyncFsType {
+    SubMsgEnd,
+}
+
 enum SubMsgNetCtlType<'a> {
     SubMsgEnd,
     SubMsgNetCtlFlags(u16),
@@ -83,6 +111,10 @@ enum SubMsgNetCtlFlags {
     Add,
 }
 
+enum SubMsgPingType {
+    SubMsgEnd,
+}
+
 enum SubMsgNetHostType<'a> {
     SubMsgEnd,
     SubMsgNetHostEntry(&'a [u8], &'a [u8]),
@@ -100,10 +132,59 @@ struct Message<T> {
 }
 
 pub struct GuestAgent {
-    stream: WriteHalf<UnixStream>,
-    last_msg_id: u64,
-    responses: mpsc::Receiver<ResponseWithId>,
-    responses_reader_handle: Option<tokio::task::JoinHandle<io::Error>>,
+    stream: Arc<Mutex<WriteHalf<UnixStream>>>,
+    timeouts: Timeouts,
+    last_msg_id: AtomicU64,
+    /// Requests awaiting a response from the guest, keyed by message id
+    pending: PendingRequests<Response>,
+    /// Task reading from the socket, finishes when the connection is lost
+    reader: std::sync::Mutex<Option<JoinHandle<()>>>,
+}
+
+/// Error of a request failed by losing the connection
+fn closed_error(err: io::Error) -> GuestAgentError {
+    match err.kind() {
+        // the reader stops on malformed messages
+        io::ErrorKind::InvalidData => GuestAgentError::Protocol(err.to_string()),
+        _ => GuestAgentError::Closed(err),
+    }
+}
+
+/// Response deadlines of guest agent requests, by request type
+#[derive(Clone, Debug)]
+pub struct Timeouts {
+    /// Waiting for the guest agent socket, i.e. for QEMU to start
+    pub connect: Duration,
+    pub quit: Duration,
+    pub run_process: Duration,
+    pub kill: Duration,
+    pub mount: Duration,
+    /// Uploading a single chunk of a file
+    pub upload: Duration,
+    pub query_output: Duration,
+    /// Writing a chunk of process input, including waiting for the process to read
+    /// earlier input
+    pub put_input: Duration,
+    pub sync_fs: Duration,
+    /// Network and hosts configuration
+    pub network: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_secs(10),
+            quit: Duration::from_secs(10),
+            run_process: Duration::from_secs(30),
+            kill: Duration::from_secs(10),
+            mount: Duration::from_secs(30),
+            upload: Duration::from_secs(60),
+            query_output: Duration::from_secs(30),
+            put_input: Duration::from_secs(30),
+            sync_fs: Duration::from_secs(300),
+            network: Duration::from_secs(30),
+        }
+    }
 }
 
 trait EncodeInto {
@@ -130,10 +211,22 @@ impl SubMsgTrait<SubMsgMountVolumeType<'_>> for SubMsgMountVolumeType<'_> {
     const TYPE: u8 = MsgType::MsgMountVolume as u8;
 }
 
+impl SubMsgTrait<SubMsgUploadFileType<'_>> for SubMsgUploadFileType<'_> {
+    const TYPE: u8 = MsgType::MsgUploadFile as u8;
+}
+
 impl SubMsgTrait<SubMsgQueryOutputType> for SubMsgQueryOutputType {
     const TYPE: u8 = MsgType::MsgQueryOutput as u8;
 }
 
+impl SubMsgTrait<SubMsgPutInputType<'_>> for SubMsgPutInputType<'_> {
+    const TYPE: u8 = MsgType::MsgPutInput as u8;
+}
+
+impl SubMsgTrait<SubMsgSyncFsType> for SubMsgSyncFsType {
+    const TYPE: u8 = MsgType::MsgSyncFs as u8;
+}
+
 impl SubMsgTrait<SubMsgNetCtlType<'_>> for SubMsgNetCtlType<'_> {
     const TYPE: u8 = MsgType::MsgNetCtl as u8;
 }
@@ -142,6 +235,10 @@ impl SubMsgTrait<SubMsgNetHostType<'_>> for SubMsgNetHostType<'_> {
     const TYPE: u8 = MsgType::MsgNetHost as u8;
 }
 
+impl SubMsgTrait<SubMsgPingType> for SubMsgPingType {
+    const TYPE: u8 = MsgType::MsgPing as u8;
+}
+
 impl EncodeInto for u8 {
     fn encode_into(&self, buf: &mut Vec<u8>) {
         buf.extend(&self.to_le_bytes());
@@ -259,6 +356,14 @@ impl EncodeInto for SubMsgKillProcessType {
                 1u8.encode_into(buf);
                 id.encode_into(buf);
             }
+            SubMsgKillProcessType::SubMsgKillProcessSignal(signal) => {
+                2u8.encode_into(buf);
+                signal.encode_into(buf);
+            }
+            SubMsgKillProcessType::SubMsgKillProcessGrace(grace_ms) => {
+                3u8.encode_into(buf);
+                grace_ms.encode_into(buf);
+            }
         }
     }
 }
@@ -281,6 +386,40 @@ impl EncodeInto for SubMsgMountVolumeType<'_> {
     }
 }
 
+impl EncodeInto for SubMsgUploadFileType<'_> {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            SubMsgUploadFileType::SubMsgEnd => {
+                0u8.encode_into(buf);
+            }
+            SubMsgUploadFileType::SubMsgUploadFilePath(path) => {
+                1u8.encode_into(buf);
+                path.encode_into(buf);
+            }
+            SubMsgUploadFileType::SubMsgUploadFilePerm(perm) => {
+                2u8.encode_into(buf);
+                perm.encode_into(buf);
+            }
+            SubMsgUploadFileType::SubMsgUploadFileUsr(uid) => {
+                3u8.encode_into(buf);
+                uid.encode_into(buf);
+            }
+            SubMsgUploadFileType::SubMsgUploadFileGrp(gid) => {
+                4u8.encode_into(buf);
+                gid.encode_into(buf);
+            }
+            SubMsgUploadFileType::SubMsgUploadFileData(data) => {
+                5u8.encode_into(buf);
+                data.encode_into(buf);
+            }
+            SubMsgUploadFileType::SubMsgUploadFileOffset(off) => {
+                6u8.encode_into(buf);
+                off.encode_into(buf);
+            }
+        }
+    }
+}
+
 impl EncodeInto for SubMsgQueryOutputType {
     fn encode_into(&self, buf: &mut Vec<u8>) {
         match self {
@@ -307,6 +446,36 @@ impl EncodeInto for SubMsgQueryOutputType {
     }
 }
 
+impl EncodeInto for SubMsgPutInputType<'_> {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            SubMsgPutInputType::SubMsgEnd => {
+                0u8.encode_into(buf);
+            }
+            SubMsgPutInputType::SubMsgPutInputId(id) => {
+                1u8.encode_into(buf);
+                id.encode_into(buf);
+            }
+            SubMsgPutInputType::SubMsgPutInputData(data) => {
+                2u8.encode_into(buf);
+                data.encode_into(buf);
+            }
+        }
+    }
+}
+
+impl EncodeInto for SubMsgSyncFsType {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        0u8.encode_into(buf);
+    }
+}
+
+impl EncodeInto for SubMsgPingType {
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        0u8.encode_into(buf);
+    }
+}
+
 impl EncodeInto for SubMsgNetCtlType<'_> {
     fn encode_into(&self, buf: &mut Vec<u8>) {
         match self {
@@ -385,161 +554,226 @@ impl<T> AsRef<Vec<u8>> for Message<T> {
     }
 }
 
-pub type RemoteCommandResult<T> = Result<T, /* exit code */ u32>;
+pub type RemoteCommandResult<T> = Result<T, GuestAgentError>;
+
+pub const SIGKILL: u32 = 9;
+pub const SIGTERM: u32 = 15;
+
+/// Maximum size of file data carried by a single upload message
+const UPLOAD_CHUNK_SIZE: usize = 1 << 20;
+/// Interval of attempts to connect to the guest agent socket
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(200);
 
 fn reader<'f, F>(
-    agent: Arc<Mutex<GuestAgent>>,
+    agent: Arc<GuestAgent>,
     mut stream: ReadHalf<UnixStream>,
     mut notification_handler: F,
-    mut responses: mpsc::Sender<ResponseWithId>,
-) -> BoxFuture<'f, io::Error>
+    pending: PendingRequests<Response>,
+) -> BoxFuture<'f, ()>
 where
-    F: FnMut(Notification, Arc<Mutex<GuestAgent>>) -> BoxFuture<'static, ()> + Send + 'static,
+    F: FnMut(Notification, Arc<GuestAgent>) -> BoxFuture<'static, ()> + Send + 'static,
 {
-    let (mut tx, rx) = mpsc::channel(8);
+    // unbounded, since handlers await responses which are read by this task
+    let (tx, rx) = mpsc::unbounded();
+    let notified = agent.clone();
     spawn(async move {
         let _ = rx
-            .for_each(|n| notification_handler(n, agent.clone()))
+            .for_each(|n| notification_handler(n, notified.clone()))
             .await;
     });
     async move {
-        loop {
+        let err = loop {
             match parse_one_response(&mut stream).await {
                 Ok(msg) => match msg {
                     GuestAgentMessage::Notification(notification) => {
-                        let _ = tx.send(notification).await;
+                        let _ = tx.unbounded_send(notification);
                     }
-                    GuestAgentMessage::Response(resp) => {
-                        responses.send(resp).await.expect("failed to send response");
+                    GuestAgentMessage::Response(ResponseWithId { id, resp }) => {
+                        if let Err(resp) = pending.complete(id, resp) {
+                            late_response(&agent, id, resp);
+                        }
                     }
                 },
-                Err(err) => return err,
+                Err(err) => break err,
             }
-        }
+        };
+
+        log::debug!("Guest Agent connection closed: {}", err);
+        pending.close(&err);
     }
     .boxed()
 }
 
+/// Handles a response to a request which is no longer awaited, e.g. after a timeout
+fn late_response(agent: &Arc<GuestAgent>, id: u64, resp: Response) {
+    match resp {
+        // only spawning a process responds with a number, the process id
+        Response::OkU64(pid) => {
+            log::warn!("Killing late-started process {} (request {})", pid, id);
+            let agent = agent.clone();
+            spawn(async move {
+                if let Err(err) = agent.kill(pid, SIGKILL, None).await {
+                    log::error!("Unable to kill process {}: {}", pid, err);
+                }
+            });
+        }
+        resp => log::debug!("Got response for abandoned request {}: {:?}", id, resp),
+    }
+}
+
 impl GuestAgent {
+    /// Connects to the guest agent socket, retrying until `timeouts.connect` elapses
+    /// while the socket does not exist.
     pub async fn connected<F, P>(
         path: P,
-        timeout: u32,
+        timeouts: Timeouts,
         notification_handler: F,
-    ) -> io::Result<Arc<Mutex<GuestAgent>>>
+    ) -> io::Result<Arc<GuestAgent>>
     where
-        F: FnMut(Notification, Arc<Mutex<GuestAgent>>) -> BoxFuture<'static, ()> + Send + 'static,
+        F: FnMut(Notification, Arc<GuestAgent>) -> BoxFuture<'static, ()> + Send + 'static,
         P: AsRef<Path>,
     {
-        let mut timeout_remaining = timeout;
-        loop {
+        let deadline = Instant::now() + timeouts.connect;
+        let stream = loop {
             match UnixStream::connect(&path).await {
-                Ok(s) => {
-                    let (stream_read, stream_write) = split(s);
-                    let (response_send, response_receive) = mpsc::channel(10);
-                    let ga = Arc::new(Mutex::new(GuestAgent {
-                        stream: stream_write,
-                        last_msg_id: 0,
-                        responses: response_receive,
-                        responses_reader_handle: None,
-                    }));
-                    let reader_handle = spawn(reader(
-                        ga.clone(),
-                        stream_read,
-                        notification_handler,
-                        response_send,
-                    ));
-                    ga.lock()
-                        .await
-                        .responses_reader_handle
-                        .replace(reader_handle);
-                    break Ok(ga);
-                }
+                Ok(s) => break s,
                 Err(err) => match err.kind() {
                     io::ErrorKind::NotFound => {
                         log::info!("Waiting for Guest Agent socket ...");
-                        if timeout_remaining > 0 {
-                            time::sleep(time::Duration::from_secs(1)).await;
-                            timeout_remaining -= 1;
-                        } else {
-                            break Err(io::Error::new(
+                        if Instant::now() >= deadline {
+                            return Err(io::Error::new(
                                 io::ErrorKind::TimedOut,
                                 "Could not connect to the Guest Agent socket",
                             ));
                         }
+                        time::sleep(CONNECT_RETRY_INTERVAL).await;
                     }
-                    _ => break Err(err),
+                    _ => return Err(err),
                 },
             };
-        }
+        };
+
+        let (stream_read, stream_write) = split(stream);
+        let pending = PendingRequests::default();
+        let ga = Arc::new(GuestAgent {
+            stream: Arc::new(Mutex::new(stream_write)),
+            timeouts,
+            last_msg_id: AtomicU64::new(0),
+            pending: pending.clone(),
+            reader: Default::default(),
+        });
+        let handle = spawn(reader(
+            ga.clone(),
+            stream_read,
+            notification_handler,
+            pending,
+        ));
+        ga.reader.lock().unwrap().replace(handle);
+        Ok(ga)
     }
 
-    fn get_new_msg_id(&mut self) -> u64 {
-        self.last_msg_id += 1;
-        self.last_msg_id
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
     }
 
-    async fn get_response(&mut self, msg_id: u64) -> io::Result<Response> {
-        let ResponseWithId { id, resp } = match self.responses.next().await {
-            Some(x) => x,
-            None => {
-                return Err(self
-                    .responses_reader_handle
-                    .take()
-                    .unwrap()
-                    .await
-                    .unwrap_or_else(|join_error| {
-                        io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("Unexpected error in reader task: {}", join_error),
-                        )
-                    }))
+    /// Takes the handle of the socket reader task, which completes once the
+    /// connection to the guest is closed
+    pub fn take_reader(&self) -> Option<JoinHandle<()>> {
+        self.reader.lock().unwrap().take()
+    }
+
+    fn get_new_msg_id(&self) -> u64 {
+        self.last_msg_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Sends a request and waits for the matching response, for at most `timeout`.
+    ///
+    /// Requests don't block each other while awaiting responses, only the writes
+    /// to the socket are serialized. Dropping the returned future is safe: the
+    /// message is either sent as a whole or not at all. A response arriving after
+    /// the timeout or drop is handled by `late_response`, which kills a process
+    /// the guest started in the meantime.
+    async fn get_response(
+        &self,
+        msg_id: u64,
+        msg: &[u8],
+        timeout: Duration,
+    ) -> RemoteCommandResult<Response> {
+        // unregistered once the response is received or awaiting it is abandoned
+        let mut request = self.pending.register(msg_id).map_err(closed_error)?;
+
+        // writing in a separate task, a partially written message would corrupt the stream
+        let stream = self.stream.clone();
+        let msg = msg.to_vec();
+        let write = spawn(async move { stream.lock().await.write_all(&msg).await });
+
+        let response = async move {
+            match write.await {
+                Ok(result) => result.map_err(GuestAgentError::Closed)?,
+                Err(err) => {
+                    let err = io::Error::new(io::ErrorKind::Other, err);
+                    return Err(GuestAgentError::Closed(err));
+                }
             }
+            request.response().await.map_err(closed_error)
         };
 
-        if id != msg_id {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Got response with different ID",
-            ));
+        match time::timeout(timeout, response).await {
+            Ok(result) => result,
+            Err(_) => Err(GuestAgentError::Timeout { msg_id, timeout }),
         }
-        Ok(resp)
     }
 
-    fn match_error<T>(resp: Response) -> io::Result<RemoteCommandResult<T>> {
+    fn match_error<T>(resp: Response) -> RemoteCommandResult<T> {
         match resp {
-            Response::Err(code) => Ok(Err(code)),
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid response",
-            )),
+            Response::Err(errno) => Err(GuestAgentError::Remote {
+                errno,
+                context: String::new(),
+            }),
+            resp => Err(GuestAgentError::Protocol(format!(
+                "Unexpected response: {:?}",
+                resp
+            ))),
         }
     }
 
-    async fn get_ok_response(&mut self, msg_id: u64) -> io::Result<RemoteCommandResult<()>> {
-        match self.get_response(msg_id).await? {
-            Response::Ok => Ok(Ok(())),
+    async fn get_ok_response(
+        &self,
+        msg_id: u64,
+        msg: &[u8],
+        timeout: Duration,
+    ) -> RemoteCommandResult<()> {
+        match self.get_response(msg_id, msg, timeout).await? {
+            Response::Ok => Ok(()),
             x => GuestAgent::match_error(x),
         }
     }
 
-    async fn get_u64_response(&mut self, msg_id: u64) -> io::Result<RemoteCommandResult<u64>> {
-        match self.get_response(msg_id).await? {
-            Response::OkU64(val) => Ok(Ok(val)),
+    async fn get_u64_response(
+        &self,
+        msg_id: u64,
+        msg: &[u8],
+        timeout: Duration,
+    ) -> RemoteCommandResult<u64> {
+        match self.get_response(msg_id, msg, timeout).await? {
+            Response::OkU64(val) => Ok(val),
             x => GuestAgent::match_error(x),
         }
     }
 
-    async fn get_bytes_response(
-        &mut self,
+    async fn get_output_response(
+        &self,
         msg_id: u64,
-    ) -> io::Result<RemoteCommandResult<Vec<u8>>> {
-        match self.get_response(msg_id).await? {
-            Response::OkBytes(bytes) => Ok(Ok(bytes)),
+        msg: &[u8],
+        timeout: Duration,
+    ) -> RemoteCommandResult<OutputChunk> {
+        match self.get_response(msg_id, msg, timeout).await? {
+            Response::OkOutput(chunk) => Ok(chunk),
             x => GuestAgent::match_error(x),
         }
     }
 
-    pub async fn quit(&mut self) -> io::Result<RemoteCommandResult<()>> {
+    pub async fn quit(&self) -> RemoteCommandResult<()> {
         let mut msg = Message::default();
         let msg_id = self.get_new_msg_id();
 
@@ -547,13 +781,37 @@ impl GuestAgent {
 
         msg.append_submsg(&SubMsgQuitType::SubMsgEnd);
 
-        self.stream.write_all(msg.as_ref()).await?;
+        let timeout = self.timeouts.quit;
+        self.get_ok_response(msg_id, msg.as_ref(), timeout).await
+    }
+
+    /// Checks whether the guest agent is responsive, waiting for at most `timeout`.
+    pub async fn ping(&self, timeout: Duration) -> RemoteCommandResult<()> {
+        let mut msg = Message::default();
+        let msg_id = self.get_new_msg_id();
+
+        msg.create_header(msg_id);
+
+        msg.append_submsg(&SubMsgPingType::SubMsgEnd);
+
+        self.get_ok_response(msg_id, msg.as_ref(), timeout).await
+    }
+
+    /// Flushes guest filesystem buffers, including writes to mounted volumes.
+    pub async fn sync_fs(&self) -> RemoteCommandResult<()> {
+        let mut msg = Message::default();
+        let msg_id = self.get_new_msg_id();
+
+        msg.create_header(msg_id);
+
+        msg.append_submsg(&SubMsgSyncFsType::SubMsgEnd);
 
-        self.get_ok_response(msg_id).await
+        let timeout = self.timeouts.sync_fs;
+        self.get_ok_response(msg_id, msg.as_ref(), timeout).await
     }
 
     async fn spawn_new_process(
-        &mut self,
+        &self,
         bin: &str,
         argv: &[&str],
         maybe_env: Option<&[&str]>,
@@ -562,7 +820,7 @@ impl GuestAgent {
         fds: &[Option<RedirectFdType<'_>>; 3],
         maybe_cwd: Option<&str>,
         is_entrypoint: bool,
-    ) -> io::Result<RemoteCommandResult<u64>> {
+    ) -> RemoteCommandResult<u64> {
         let mut msg = Message::default();
         let msg_id = self.get_new_msg_id();
 
@@ -601,13 +859,17 @@ impl GuestAgent {
 
         msg.append_submsg(&SubMsgRunProcessType::SubMsgEnd);
 
-        self.stream.write_all(msg.as_ref()).await?;
-
-        self.get_u64_response(msg_id).await
+        self.get_u64_response(msg_id, msg.as_ref(), self.timeouts.run_process)
+            .await
+            .map_err(|e| e.context(bin))
     }
 
+    /// Spawns a process, returning its id.
+    ///
+    /// If the request times out, the guest may still start the process. Its id is
+    /// then never returned and the process is killed once the late response arrives.
     pub async fn run_process(
-        &mut self,
+        &self,
         bin: &str,
         argv: &[&str],
         maybe_env: Option<&[&str]>,
@@ -615,7 +877,7 @@ impl GuestAgent {
         gid: u32,
         fds: &[Option<RedirectFdType<'_>>; 3],
         maybe_cwd: Option<&str>,
-    ) -> io::Result<RemoteCommandResult<u64>> {
+    ) -> RemoteCommandResult<u64> {
         self.spawn_new_process(
             bin, argv, maybe_env, uid, gid, fds, maybe_cwd, /*is_entrypoint=*/ false,
         )
@@ -623,7 +885,7 @@ impl GuestAgent {
     }
 
     pub async fn run_entrypoint(
-        &mut self,
+        &self,
         bin: &str,
         argv: &[&str],
         maybe_env: Option<&[&str]>,
@@ -631,29 +893,43 @@ impl GuestAgent {
         gid: u32,
         fds: &[Option<RedirectFdType<'_>>; 3],
         maybe_cwd: Option<&str>,
-    ) -> io::Result<RemoteCommandResult<u64>> {
+    ) -> RemoteCommandResult<u64> {
         self.spawn_new_process(
             bin, argv, maybe_env, uid, gid, fds, maybe_cwd, /*is_entrypoint=*/ true,
         )
         .await
     }
 
-    pub async fn kill(&mut self, id: u64) -> io::Result<RemoteCommandResult<()>> {
+    /// Sends `signal` to a process.
+    ///
+    /// With a `grace_period`, the guest follows up with `SIGKILL` if the process
+    /// is still alive once the period elapses.
+    pub async fn kill(
+        &self,
+        id: u64,
+        signal: u32,
+        grace_period: Option<Duration>,
+    ) -> RemoteCommandResult<()> {
         let mut msg = Message::default();
         let msg_id = self.get_new_msg_id();
 
         msg.create_header(msg_id);
 
         msg.append_submsg(&SubMsgKillProcessType::SubMsgKillProcessId(id));
+        msg.append_submsg(&SubMsgKillProcessType::SubMsgKillProcessSignal(signal));
+        if let Some(grace_period) = grace_period {
+            let grace_ms = u32::try_from(grace_period.as_millis()).unwrap_or(u32::MAX);
+            msg.append_submsg(&SubMsgKillProcessType::SubMsgKillProcessGrace(grace_ms));
+        }
 
         msg.append_submsg(&SubMsgKillProcessType::SubMsgEnd);
 
-        self.stream.write_all(msg.as_ref()).await?;
-
-        self.get_ok_response(msg_id).await
+        self.get_ok_response(msg_id, msg.as_ref(), self.timeouts.kill)
+            .await
+            .map_err(|e| e.context(format!("process {}", id)))
     }
 
-    pub async fn mount(&mut self, tag: &str, path: &str) -> io::Result<RemoteCommandResult<()>> {
+    pub async fn mount(&self, tag: &str, path: &str) -> RemoteCommandResult<()> {
         let mut msg = Message::default();
         let msg_id = self.get_new_msg_id();
 
@@ -667,12 +943,12 @@ impl GuestAgent {
 
         msg.append_submsg(&SubMsgMountVolumeType::SubMsgEnd);
 
-        self.stream.write_all(msg.as_ref()).await?;
-
-        self.get_ok_response(msg_id).await
+        self.get_ok_response(msg_id, msg.as_ref(), self.timeouts.mount)
+            .await
+            .map_err(|e| e.context(path))
     }
 
-    pub async fn add_hosts<'a, I, T, S>(&mut self, hosts: I) -> io::Result<RemoteCommandResult<()>>
+    pub async fn add_hosts<'a, I, T, S>(&self, hosts: I) -> RemoteCommandResult<()>
     where
         I: Iterator<Item = (T, S)>,
         T: AsRef<str>,
@@ -690,17 +966,17 @@ impl GuestAgent {
         }
         msg.append_submsg(&SubMsgNetHostType::SubMsgEnd);
 
-        self.stream.write_all(msg.as_ref()).await?;
-        self.get_ok_response(msg_id).await
+        let timeout = self.timeouts.network;
+        self.get_ok_response(msg_id, msg.as_ref(), timeout).await
     }
 
     pub async fn create_network(
-        &mut self,
+        &self,
         addr: &str,
         mask: &str,
         gateway: &str,
         iface: u16,
-    ) -> io::Result<RemoteCommandResult<()>> {
+    ) -> RemoteCommandResult<()> {
         let mut msg = Message::default();
         let msg_id = self.get_new_msg_id();
         let flags = SubMsgNetCtlFlags::Add as u16;
@@ -713,16 +989,17 @@ impl GuestAgent {
         msg.append_submsg(&SubMsgNetCtlType::SubMsgNetCtlIf(iface));
         msg.append_submsg(&SubMsgNetCtlType::SubMsgEnd);
 
-        self.stream.write_all(msg.as_ref()).await?;
-        self.get_ok_response(msg_id).await
+        self.get_ok_response(msg_id, msg.as_ref(), self.timeouts.network)
+            .await
+            .map_err(|e| e.context(addr))
     }
 
     pub async fn add_address(
-        &mut self,
+        &self,
         if_addr: &str,
         mask: &str,
         iface: u16,
-    ) -> io::Result<RemoteCommandResult<()>> {
+    ) -> RemoteCommandResult<()> {
         let mut msg = Message::default();
         let msg_id = self.get_new_msg_id();
         let flags = SubMsgNetCtlFlags::Add as u16;
@@ -734,17 +1011,23 @@ impl GuestAgent {
         msg.append_submsg(&SubMsgNetCtlType::SubMsgNetCtlIf(iface));
         msg.append_submsg(&SubMsgNetCtlType::SubMsgEnd);
 
-        self.stream.write_all(msg.as_ref()).await?;
-        self.get_ok_response(msg_id).await
+        self.get_ok_response(msg_id, msg.as_ref(), self.timeouts.network)
+            .await
+            .map_err(|e| e.context(if_addr))
     }
 
+    /// Reads up to `len` bytes of process output.
+    ///
+    /// Output buffered in a pipe is consumed by reading and `off` is ignored.
+    /// The returned chunk carries its offset in the output stream, so gaps left by
+    /// data dropped from a full `RedirectFdPipeCyclic` buffer can be detected.
     pub async fn query_output(
-        &mut self,
+        &self,
         id: u64,
         fd: u8,
         off: u64,
         len: u64,
-    ) -> io::Result<RemoteCommandResult<Vec<u8>>> {
+    ) -> RemoteCommandResult<OutputChunk> {
         let mut msg = Message::default();
         let msg_id = self.get_new_msg_id();
 
@@ -757,8 +1040,101 @@ impl GuestAgent {
 
         msg.append_submsg(&SubMsgQueryOutputType::SubMsgEnd);
 
-        self.stream.write_all(msg.as_ref()).await?;
+        self.get_output_response(msg_id, msg.as_ref(), self.timeouts.query_output)
+            .await
+            .map_err(|e| e.context(format!("process {}", id)))
+    }
+
+    /// Writes `data` to the stdin pipe of a process; empty `data` closes the pipe.
+    ///
+    /// The process must have been spawned with fd 0 redirected to a pipe. The guest
+    /// rejects data exceeding the free space of the pipe buffer with `EAGAIN`.
+    pub async fn put_input(&self, id: u64, data: &[u8]) -> RemoteCommandResult<()> {
+        let mut msg = Message::default();
+        let msg_id = self.get_new_msg_id();
+
+        msg.create_header(msg_id);
+
+        msg.append_submsg(&SubMsgPutInputType::SubMsgPutInputId(id));
+        msg.append_submsg(&SubMsgPutInputType::SubMsgPutInputData(data));
+
+        msg.append_submsg(&SubMsgPutInputType::SubMsgEnd);
+
+        self.get_ok_response(msg_id, msg.as_ref(), self.timeouts.put_input)
+            .await
+            .map_err(|e| e.context(format!("process {}", id)))
+    }
+
+    /// Creates a file in the guest filesystem and fills it with the contents of `reader`.
+    ///
+    /// Parent directories are created as needed. Data is sent in chunks of at most
+    /// `UPLOAD_CHUNK_SIZE` bytes, so files of any size can be uploaded.
+    pub async fn upload_file<R>(
+        &self,
+        path: &str,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        mut reader: R,
+    ) -> RemoteCommandResult<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = vec![0u8; UPLOAD_CHUNK_SIZE];
+        let mut offset = 0u64;
+
+        loop {
+            let mut len = 0;
+            while len < buf.len() {
+                match reader
+                    .read(&mut buf[len..])
+                    .await
+                    .map_err(GuestAgentError::Io)?
+                {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+
+            // the first chunk creates the file, even if it's empty
+            if len > 0 || offset == 0 {
+                let chunk = &buf[..len];
+                self.upload_chunk(path, mode, uid, gid, offset, chunk)
+                    .await?;
+                offset += len as u64;
+            }
+
+            if len < buf.len() {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn upload_chunk(
+        &self,
+        path: &str,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+        offset: u64,
+        data: &[u8],
+    ) -> RemoteCommandResult<()> {
+        let mut msg = Message::default();
+        let msg_id = self.get_new_msg_id();
+
+        msg.create_header(msg_id);
+
+        msg.append_submsg(&SubMsgUploadFileType::SubMsgUploadFilePath(path.as_bytes()));
+        msg.append_submsg(&SubMsgUploadFileType::SubMsgUploadFilePerm(mode));
+        msg.append_submsg(&SubMsgUploadFileType::SubMsgUploadFileUsr(uid));
+        msg.append_submsg(&SubMsgUploadFileType::SubMsgUploadFileGrp(gid));
+        msg.append_submsg(&SubMsgUploadFileType::SubMsgUploadFileOffset(offset));
+        msg.append_submsg(&SubMsgUploadFileType::SubMsgUploadFileData(data));
+
+        msg.append_submsg(&SubMsgUploadFileType::SubMsgEnd);
 
-        self.get_bytes_response(msg_id).await
+        self.get_ok_response(msg_id, msg.as_ref(), self.timeouts.upload)
+            .await
+            .map_err(|e| e.context(path))
     }
 }
@@ -0,0 +1,179 @@
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// Failure of a guest agent request
+#[derive(Debug)]
+pub enum GuestAgentError {
+    /// Connection to the guest agent is closed or unusable
+    Closed(io::Error),
+    /// The guest agent sent an unexpected or malformed message
+    Protocol(String),
+    /// The request failed in the guest with an `errno` code
+    Remote { errno: u32, context: String },
+    /// No response was received within the deadline
+    Timeout { msg_id: u64, timeout: Duration },
+    /// Host-side I/O error, e.g. while reading a file to upload
+    Io(io::Error),
+}
+
+impl GuestAgentError {
+    /// Describes the subject of a remote failure, e.g. a path
+    pub fn context(self, context: impl ToString) -> Self {
+        match self {
+            GuestAgentError::Remote { errno, .. } => GuestAgentError::Remote {
+                errno,
+                context: context.to_string(),
+            },
+            err => err,
+        }
+    }
+
+    /// Error code of a remote failure
+    pub fn errno(&self) -> Option<u32> {
+        match self {
+            GuestAgentError::Remote { errno, .. } => Some(*errno),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for GuestAgentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GuestAgentError::Closed(err) => write!(f, "Guest Agent connection closed: {}", err),
+            GuestAgentError::Protocol(msg) => write!(f, "Guest Agent protocol error: {}", msg),
+            GuestAgentError::Remote { errno, context } => {
+                match errno_name(*errno) {
+                    Some(name) => write!(f, "{}", name)?,
+                    None => write!(f, "error {}", errno)?,
+                }
+                if !context.is_empty() {
+                    write!(f, ": {}", context)?;
+                }
+                Ok(())
+            }
+            GuestAgentError::Timeout { msg_id, timeout } => write!(
+                f,
+                "Guest Agent request {} timed out after {:?}",
+                msg_id, timeout
+            ),
+            GuestAgentError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for GuestAgentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GuestAgentError::Closed(err) | GuestAgentError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<GuestAgentError> for io::Error {
+    fn from(err: GuestAgentError) -> Self {
+        let kind = match &err {
+            GuestAgentError::Closed(err) | GuestAgentError::Io(err) => err.kind(),
+            GuestAgentError::Protocol(_) => io::ErrorKind::InvalidData,
+            GuestAgentError::Remote { errno, .. } => {
+                io::Error::from_raw_os_error(*errno as i32).kind()
+            }
+            GuestAgentError::Timeout { .. } => io::ErrorKind::TimedOut,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// Symbolic name of a Linux `errno` code, as reported by the guest
+pub fn errno_name(errno: u32) -> Option<&'static str> {
+    let name = match errno as i32 {
+        libc::EPERM => "EPERM",
+        libc::ENOENT => "ENOENT",
+        libc::ESRCH => "ESRCH",
+        libc::EINTR => "EINTR",
+        libc::EIO => "EIO",
+        libc::ENXIO => "ENXIO",
+        libc::E2BIG => "E2BIG",
+        libc::ENOEXEC => "ENOEXEC",
+        libc::EBADF => "EBADF",
+        libc::ECHILD => "ECHILD",
+        libc::EAGAIN => "EAGAIN",
+        libc::ENOMEM => "ENOMEM",
+        libc::EACCES => "EACCES",
+        libc::EFAULT => "EFAULT",
+        libc::EBUSY => "EBUSY",
+        libc::EEXIST => "EEXIST",
+        libc::EXDEV => "EXDEV",
+        libc::ENODEV => "ENODEV",
+        libc::ENOTDIR => "ENOTDIR",
+        libc::EISDIR => "EISDIR",
+        libc::EINVAL => "EINVAL",
+        libc::ENFILE => "ENFILE",
+        libc::EMFILE => "EMFILE",
+        libc::ETXTBSY => "ETXTBSY",
+        libc::EFBIG => "EFBIG",
+        libc::ENOSPC => "ENOSPC",
+        libc::ESPIPE => "ESPIPE",
+        libc::EROFS => "EROFS",
+        libc::EMLINK => "EMLINK",
+        libc::EPIPE => "EPIPE",
+        libc::ERANGE => "ERANGE",
+        libc::ENAMETOOLONG => "ENAMETOOLONG",
+        libc::ENOSYS => "ENOSYS",
+        libc::ENOTEMPTY => "ENOTEMPTY",
+        libc::ELOOP => "ELOOP",
+        libc::EOVERFLOW => "EOVERFLOW",
+        libc::ENOTSOCK => "ENOTSOCK",
+        libc::ENOPROTOOPT => "ENOPROTOOPT",
+        libc::EOPNOTSUPP => "EOPNOTSUPP",
+        libc::EADDRINUSE => "EADDRINUSE",
+        libc::EADDRNOTAVAIL => "EADDRNOTAVAIL",
+        libc::ENETUNREACH => "ENETUNREACH",
+        libc::ECONNRESET => "ECONNRESET",
+        libc::ETIMEDOUT => "ETIMEDOUT",
+        libc::ECONNREFUSED => "ECONNREFUSED",
+        libc::EHOSTUNREACH => "EHOSTUNREACH",
+        _ => return None,
+    };
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errno_names() {
+        assert_eq!(errno_name(2), Some("ENOENT"));
+        assert_eq!(errno_name(libc::EHOSTUNREACH as u32), Some("EHOSTUNREACH"));
+        assert_eq!(errno_name(0), None);
+        assert_eq!(errno_name(9999), None);
+    }
+
+    #[test]
+    fn display_remote() {
+        let err = GuestAgentError::Remote {
+            errno: 2,
+            context: String::new(),
+        };
+        assert_eq!(err.to_string(), "ENOENT");
+        assert_eq!(err.context("/bin/foo").to_string(), "ENOENT: /bin/foo");
+
+        let err = GuestAgentError::Remote {
+            errno: 9999,
+            context: "/bin/foo".to_string(),
+        };
+        assert_eq!(err.to_string(), "error 9999: /bin/foo");
+    }
+
+    #[test]
+    fn context_of_local_error() {
+        let err = GuestAgentError::Protocol("Unexpected response".to_string());
+        assert_eq!(
+            err.context("/bin/foo").to_string(),
+            "Guest Agent protocol error: Unexpected response"
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+const PROC_MEMINFO: &'static str = "/proc/meminfo";
+const SYSFS_CPU_ONLINE: &'static str = "/sys/devices/system/cpu/online";
+const DEV_KVM: &'static str = "/dev/kvm";
+
+/// Hardware resources of the host
+pub struct HostInfo {
+    /// Number of online logical CPUs
+    pub threads: usize,
+    /// Total memory [B]
+    pub memory: u64,
+    /// Disk space available in the work directory [B]
+    pub storage: u64,
+    /// Whether `/dev/kvm` can be opened for use by the VM
+    pub kvm: bool,
+}
+
+impl HostInfo {
+    pub fn try_new(work_dir: &Path) -> anyhow::Result<HostInfo> {
+        Ok(HostInfo {
+            threads: online_cpus()?,
+            memory: total_memory()?,
+            storage: available_space(work_dir)?,
+            kvm: fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(DEV_KVM)
+                .is_ok(),
+        })
+    }
+
+    /// Default offer constraints, limiting requested resources to the host totals
+    pub fn constraints(&self) -> String {
+        format!(
+            "(&(golem.inf.cpu.threads<={})(golem.inf.mem.gib<={})(golem.inf.storage.gib<={}))",
+            self.threads,
+            gib(self.memory),
+            gib(self.storage)
+        )
+    }
+}
+
+/// Bytes to whole GiB, rounded down
+fn gib(bytes: u64) -> u64 {
+    bytes / (1024 * 1024 * 1024)
+}
+
+fn online_cpus() -> anyhow::Result<usize> {
+    count_cpu_list(&fs::read_to_string(SYSFS_CPU_ONLINE)?)
+}
+
+/// Counts CPUs in the `0-3,6,8-9` list format
+pub(crate) fn count_cpu_list(list: &str) -> anyhow::Result<usize> {
+    let invalid = || anyhow::anyhow!("Invalid CPU list: {}", list.trim());
+
+    let mut count = 0;
+    for range in list.trim().split(',') {
+        count += match range.split_once('-') {
+            Some((first, last)) => {
+                let first: usize = first.parse().map_err(|_| invalid())?;
+                let last: usize = last.parse().map_err(|_| invalid())?;
+                last.checked_sub(first).ok_or_else(invalid)? + 1
+            }
+            None => range.parse::<usize>().map(|_| 1).map_err(|_| invalid())?,
+        };
+    }
+    Ok(count)
+}
+
+fn total_memory() -> anyhow::Result<u64> {
+    let meminfo = fs::read_to_string(PROC_MEMINFO)?;
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemTotal:"))
+        .and_then(|value| value.trim().strip_suffix("kB"))
+        .and_then(|kib| kib.trim().parse::<u64>().ok())
+        .map(|kib| kib * 1024)
+        .ok_or_else(|| anyhow::anyhow!("Unable to read total memory"))
+}
+
+fn available_space(path: &Path) -> anyhow::Result<u64> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(anyhow::anyhow!(
+            "Unable to read available space in {}: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_list() {
+        assert_eq!(count_cpu_list("0\n").unwrap(), 1);
+        assert_eq!(count_cpu_list("0-3,6,8-9\n").unwrap(), 7);
+        assert!(count_cpu_list("3-1").is_err());
+        assert!(count_cpu_list("").is_err());
+    }
+
+    #[test]
+    fn constraints() {
+        let host = HostInfo {
+            threads: 16,
+            memory: 64 * 1024 * 1024 * 1024 + 512,
+            storage: 100 * 1024 * 1024 * 1024,
+            kvm: true,
+        };
+        assert_eq!(
+            host.constraints(),
+            "(&(golem.inf.cpu.threads<=16)(golem.inf.mem.gib<=64)(golem.inf.storage.gib<=100))"
+        );
+    }
+}
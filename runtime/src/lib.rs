@@ -3,5 +3,12 @@ pub mod gpu;
 pub mod cpu;
 pub mod deploy;
 pub mod guest_agent_comm;
+mod guest_agent_error;
+pub mod host;
+pub mod output;
+mod pending;
+pub mod qemu;
+pub mod qmp;
 mod response_parser;
+pub mod supervisor;
 pub mod vmrt;
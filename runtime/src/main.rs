@@ -1,16 +1,16 @@
 use std::convert::TryFrom;
+use std::future::Future;
 use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use bollard_stubs::models::ContainerConfig;
 use futures::future::FutureExt;
 use futures::lock::Mutex;
 use futures::TryFutureExt;
 use structopt::StructOpt;
-use tokio::{
-    fs,
-    io::{self, AsyncWriteExt},
-};
+use tokio::{fs, io::AsyncWriteExt, time};
 use url::Url;
 
 use ya_runtime_sdk::runtime_api::deploy::ContainerEndpoint;
@@ -26,15 +26,26 @@ use ya_runtime_sdk::{
 };
 use ya_runtime_vm::{
     cpu::CpuInfo,
+    deploy::{merge_env, parse_user_spec, Deployment, OverlayUpper},
+    detect_pci::PciAddress,
     gpu::GpuInfo,
-    deploy::Deployment,
-    guest_agent_comm::{RedirectFdType, RemoteCommandResult},
+    guest_agent_comm::{RedirectFdType, RemoteCommandResult, Timeouts, SIGTERM},
+    host::HostInfo,
+    output::OutputMode,
     vmrt::{runtime_dir, start_vmrt, RuntimeData},
 };
 
 const FILE_TEST_IMAGE: &'static str = "self-test.gvmi";
 const FILE_DEPLOYMENT: &'static str = "deployment.json";
 const DEFAULT_CWD: &'static str = "/";
+const STDIN_BUFFER_SIZE: usize = 0x1000;
+const STDIN_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+const GIB: f64 = (1u64 << 30) as f64;
+
+const CMD_RUN: &'static str = "@run";
+const CMD_PUT_INPUT: &'static str = "@put-input";
+const CMD_CLOSE_INPUT: &'static str = "@close-input";
+const CMD_SYNC_FS: &'static str = "@sync-fs";
 
 #[derive(StructOpt, Clone, Default)]
 #[structopt(rename_all = "kebab-case")]
@@ -55,15 +66,81 @@ pub struct Cli {
     #[structopt(long, default_value = "0.25")]
     mem_gib: f64,
     /// Amount of disk storage [GiB]
-    #[allow(unused)]
     #[structopt(long, default_value = "0.25")]
     storage_gib: f64,
+    /// Backing of the writable root filesystem layer: `disk` (of `storage-gib` size)
+    /// or `tmpfs[:<size MiB>]`
+    #[structopt(long, default_value = "disk")]
+    overlay: OverlayUpper,
     /// VPN endpoint address
     #[structopt(long)]
     vpn_endpoint: Option<Url>,
     /// INET endpoint address
     #[structopt(long)]
     inet_endpoint: Option<Url>,
+    /// Time given to a terminated process to exit before it gets killed [s]
+    #[structopt(long, default_value = "5")]
+    kill_grace_period: u64,
+    /// Time given to the VM to sync filesystems and power off before QEMU is terminated [s]
+    #[structopt(long, default_value = "10")]
+    stop_timeout: u64,
+    /// Time given to the VM to start and connect to the guest agent [s]
+    #[structopt(long, default_value = "10")]
+    boot_timeout: u64,
+    /// Time given to the guest agent to spawn a process [s]
+    #[structopt(long, default_value = "30")]
+    run_timeout: u64,
+    /// Time given to the guest agent to flush filesystem buffers [s]
+    #[structopt(long, default_value = "300")]
+    sync_timeout: u64,
+    /// Default stdout redirection: `blocking:<size>` or `cyclic:<size>`
+    #[structopt(long, default_value = "cyclic:4096", parse(try_from_str = parse_default_output))]
+    stdout: OutputMode,
+    /// Default stderr redirection: `blocking:<size>` or `cyclic:<size>`
+    #[structopt(long, default_value = "cyclic:4096", parse(try_from_str = parse_default_output))]
+    stderr: OutputMode,
+    /// PCI address of a GPU bound to vfio-pci to pass through to the VM; may be repeated
+    #[structopt(long = "gpu", number_of_values = 1)]
+    gpus: Vec<PciAddress>,
+}
+
+/// Options of `@run`, which spawns a process with non-default settings, e.g.
+/// `@run --stdout file:/golem/output/log -- /bin/make make all`
+#[derive(StructOpt, Debug, Default)]
+#[structopt(rename_all = "kebab-case")]
+struct RunOptions {
+    /// Stdout redirection: `file:<path>`, `blocking:<size>` or `cyclic:<size>`
+    #[structopt(long)]
+    stdout: Option<OutputMode>,
+    /// Stderr redirection: `file:<path>`, `blocking:<size>` or `cyclic:<size>`
+    #[structopt(long)]
+    stderr: Option<OutputMode>,
+    /// Environment variable to add or override: `<name>=<value>`
+    #[structopt(long, number_of_values = 1, parse(try_from_str = parse_env_var))]
+    env: Vec<String>,
+    /// User to run as: `<uid>:<gid>`
+    #[structopt(long, parse(try_from_str = parse_user_spec))]
+    user: Option<(u32, u32)>,
+    /// Working directory (absolute path)
+    #[structopt(long, parse(try_from_str = parse_cwd))]
+    cwd: Option<String>,
+    /// Binary to run, followed by its arguments (including argv[0])
+    #[structopt(required = true, min_values = 2)]
+    command: Vec<String>,
+}
+
+impl RunOptions {
+    /// Strips `@run` options from `run`, leaving the process to spawn
+    fn extract(run: &mut server::RunProcess) -> anyhow::Result<Option<Self>> {
+        if run.bin != CMD_RUN {
+            return Ok(None);
+        }
+        let mut options = RunOptions::from_iter_safe(&run.args)?;
+        let mut command = std::mem::take(&mut options.command).into_iter();
+        run.bin = command.next().unwrap_or_default();
+        run.args = command.collect();
+        Ok(Some(options))
+    }
 }
 
 #[derive(ya_runtime_sdk::RuntimeDef, Default)]
@@ -104,6 +181,16 @@ impl ya_runtime_sdk::Runtime for Runtime {
         let cmd_args = ctx.cli.command.args();
         log::debug!("Start command parameters: {cmd_args:?}");
 
+        let interactive = cmd_args.iter().any(|arg| *arg == "interactive");
+        let stdout = ctx.cli.runtime.stdout.clone();
+        let stderr = ctx.cli.runtime.stderr.clone();
+        let gpus = ctx.cli.runtime.gpus.clone();
+        let timeouts = Timeouts {
+            connect: Duration::from_secs(ctx.cli.runtime.boot_timeout),
+            run_process: Duration::from_secs(ctx.cli.runtime.run_timeout),
+            sync_fs: Duration::from_secs(ctx.cli.runtime.sync_timeout),
+            ..Default::default()
+        };
         let entrypoint = if cmd_args.iter().any(|arg| *arg == "start-entrypoint") {
             match extract_entrypoint(&deployment.config) {
                 None => return async {
@@ -132,6 +219,11 @@ impl ya_runtime_sdk::Runtime for Runtime {
                 }
 
                 data.deployment.replace(deployment);
+                data.interactive = interactive;
+                data.stdout = stdout;
+                data.stderr = stderr;
+                data.gpus = gpus;
+                data.timeouts = timeouts;
             }
 
             let start_response = start(workdir, data.clone(), emitter).await?;
@@ -144,15 +236,18 @@ impl ya_runtime_sdk::Runtime for Runtime {
         .boxed_local()
     }
 
-    fn stop<'a>(&mut self, _: &mut Context<Self>) -> EmptyResponse<'a> {
-        stop(self.data.clone()).map_err(Into::into).boxed_local()
+    fn stop<'a>(&mut self, ctx: &mut Context<Self>) -> EmptyResponse<'a> {
+        let timeout = Duration::from_secs(ctx.cli.runtime.stop_timeout);
+        stop(self.data.clone(), timeout)
+            .map_err(Into::into)
+            .boxed_local()
     }
 
     fn run_command<'a>(
         &mut self,
         command: server::RunProcess,
         mode: RuntimeMode,
-        _: &mut Context<Self>,
+        ctx: &mut Context<Self>,
     ) -> ProcessIdResponse<'a> {
         if let RuntimeMode::Command = mode {
             return async move { Err(anyhow::anyhow!("CLI `run` is not supported")) }
@@ -160,7 +255,17 @@ impl ya_runtime_sdk::Runtime for Runtime {
                 .boxed_local();
         }
 
-        run_command(self.data.clone(), command)
+        if let Some(result) = RuntimeCommand::parse(&command) {
+            let emitter = ctx
+                .emitter
+                .clone()
+                .expect("Service not running in Server mode");
+            return run_runtime_command(self.data.clone(), result, emitter)
+                .map_err(Into::into)
+                .boxed_local();
+        }
+
+        run_command(self.data.clone(), command, false)
             .map_err(Into::into)
             .boxed_local()
     }
@@ -168,15 +273,25 @@ impl ya_runtime_sdk::Runtime for Runtime {
     fn kill_command<'a>(
         &mut self,
         kill: server::KillProcess,
-        _: &mut Context<Self>,
+        ctx: &mut Context<Self>,
     ) -> EmptyResponse<'a> {
-        kill_command(self.data.clone(), kill)
+        let grace_period = Duration::from_secs(ctx.cli.runtime.kill_grace_period);
+        kill_command(self.data.clone(), kill, grace_period)
             .map_err(Into::into)
             .boxed_local()
     }
 
-    fn offer<'a>(&mut self, _: &mut Context<Self>) -> OutputResponse<'a> {
-        async move { Ok(offer()?) }.boxed_local()
+    fn offer<'a>(&mut self, ctx: &mut Context<Self>) -> OutputResponse<'a> {
+        let gpus = ctx.cli.runtime.gpus.clone();
+        let workdir = ctx.cli.workdir.clone();
+        async move {
+            let workdir = match workdir {
+                Some(workdir) => workdir,
+                None => std::env::current_dir()?,
+            };
+            Ok(offer(&workdir, &gpus)?)
+        }
+        .boxed_local()
     }
 
     fn test<'a>(&mut self, _: &mut Context<Self>) -> EmptyResponse<'a> {
@@ -203,6 +318,8 @@ async fn deploy(workdir: PathBuf, cli: Cli) -> anyhow::Result<Option<serialize::
         package_file,
         cli.cpu_cores,
         (cli.mem_gib * 1024.) as usize,
+        (cli.storage_gib * 1024.) as usize,
+        cli.overlay,
         package_path,
     )
     .await
@@ -212,6 +329,11 @@ async fn deploy(workdir: PathBuf, cli: Cli) -> anyhow::Result<Option<serialize::
         fs::create_dir_all(work_dir.join(&vol.name)).await?;
     }
 
+    deployment
+        .create_storage(&work_dir)
+        .await
+        .or_err("Error creating storage disk")?;
+
     fs::OpenOptions::new()
         .create(true)
         .write(true)
@@ -236,19 +358,43 @@ async fn start(
     start_vmrt(work_dir, runtime_data, emitter).await
 }
 
+/// Spawns a process in the VM. The `entrypoint` process is tracked by the guest
+/// init, which shuts the VM down once that process exits and its output is read.
 async fn run_command(
     runtime_data: Arc<Mutex<RuntimeData>>,
-    run: server::RunProcess,
+    mut run: server::RunProcess,
+    entrypoint: bool,
 ) -> Result<ProcessId, server::ErrorResponse> {
-    let data = runtime_data.lock().await;
-    let deployment = data.deployment().expect("Runtime not started");
+    let options = RunOptions::extract(&mut run)
+        .map_err(|e| server::ErrorResponse::msg(e.to_string()))?
+        .unwrap_or_default();
+
+    let (deployment, ga, output, entrypoint_id, interactive, stdout, stderr) = {
+        let data = runtime_data.lock().await;
+        if let Some(false) = data.runtime.as_ref().map(|vm| vm.is_healthy()) {
+            return Err(server::ErrorResponse::msg("VM is not healthy"));
+        }
+        let deployment = data.deployment().expect("Runtime not started").clone();
+        let stdout = options.stdout.unwrap_or_else(|| data.stdout.clone());
+        let stderr = options.stderr.unwrap_or_else(|| data.stderr.clone());
+        (
+            deployment,
+            data.ga().unwrap(),
+            data.output.clone(),
+            data.entrypoint.clone(),
+            data.interactive,
+            stdout,
+            stderr,
+        )
+    };
 
-    let (uid, gid) = deployment.user;
-    let env = deployment.env();
-    let cwd = deployment
-        .config
-        .working_dir
+    // `run.work_dir` is a path on the host, thus not applicable inside the VM
+    let (uid, gid) = options.user.unwrap_or(deployment.user);
+    let env = merge_env(deployment.env(), &options.env);
+    let cwd = options
+        .cwd
         .as_ref()
+        .or(deployment.config.working_dir.as_ref())
         .filter(|s| !s.trim().is_empty())
         .map(|s| s.as_str())
         .unwrap_or_else(|| DEFAULT_CWD);
@@ -256,97 +402,355 @@ async fn run_command(
     log::debug!("got run process: {:?}", run);
     log::debug!("work dir: {:?}", deployment.config.working_dir);
 
-    let result = data
+    let argv = run.args.iter().map(|s| s.as_ref()).collect::<Vec<&str>>();
+    let env = env.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+    let fds = [
+        interactive.then_some(RedirectFdType::RedirectFdPipeBlocking(
+            STDIN_BUFFER_SIZE as u64,
+        )),
+        Some(stdout.redirect()),
+        Some(stderr.redirect()),
+    ];
+
+    // the exit of the process is handled once it's registered
+    let mut entrypoint_id = entrypoint_id.lock().await;
+    let result = if entrypoint {
+        ga.run_entrypoint(&run.bin, &argv, Some(&env), uid, gid, &fds, Some(cwd))
+            .await
+    } else {
+        ga.run_process(&run.bin, &argv, Some(&env), uid, gid, &fds, Some(cwd))
+            .await
+    };
+    if let Ok(id) = result {
+        output.register(id, 1, &stdout);
+        output.register(id, 2, &stderr);
+        if entrypoint {
+            *entrypoint_id = id;
+        }
+    }
+    drop(entrypoint_id);
+
+    Ok(convert_result(result, "Running process")?)
+}
+
+/// Commands executed by the runtime itself instead of being spawned in the VM.
+/// Process input is passed as a command argument, thus it has to be valid UTF-8.
+enum RuntimeCommand {
+    PutInput { pid: ProcessId, data: Vec<u8> },
+    CloseInput { pid: ProcessId },
+    SyncFs,
+}
+
+impl RuntimeCommand {
+    fn parse(run: &server::RunProcess) -> Option<anyhow::Result<Self>> {
+        // skip argv[0]
+        let args = run.args.iter().skip(1).collect::<Vec<_>>();
+        let result = match run.bin.as_str() {
+            CMD_PUT_INPUT => match args.as_slice() {
+                [pid, data] => parse_pid(pid).map(|pid| RuntimeCommand::PutInput {
+                    pid,
+                    data: data.as_bytes().to_vec(),
+                }),
+                _ => Err(anyhow::anyhow!("Usage: {} <pid> <data>", CMD_PUT_INPUT)),
+            },
+            CMD_CLOSE_INPUT => match args.as_slice() {
+                [pid] => parse_pid(pid).map(|pid| RuntimeCommand::CloseInput { pid }),
+                _ => Err(anyhow::anyhow!("Usage: {} <pid>", CMD_CLOSE_INPUT)),
+            },
+            CMD_SYNC_FS => match args.as_slice() {
+                [] => Ok(RuntimeCommand::SyncFs),
+                _ => Err(anyhow::anyhow!("Usage: {}", CMD_SYNC_FS)),
+            },
+            _ => return None,
+        };
+        Some(result)
+    }
+}
+
+fn parse_env_var(var: &str) -> anyhow::Result<String> {
+    match var.split_once('=') {
+        Some((name, _)) if !name.is_empty() => Ok(var.to_string()),
+        _ => Err(anyhow::anyhow!("Invalid environment variable: {}", var)),
+    }
+}
+
+/// Parses the runtime-wide redirection, which can't be a file shared by all processes
+fn parse_default_output(mode: &str) -> anyhow::Result<OutputMode> {
+    match mode.parse()? {
+        OutputMode::File(_) => anyhow::bail!("File redirection can only be set per process"),
+        mode => Ok(mode),
+    }
+}
+
+fn parse_cwd(cwd: &str) -> anyhow::Result<String> {
+    if !cwd.starts_with('/') {
+        anyhow::bail!("Working directory must be absolute: {}", cwd);
+    }
+    Ok(cwd.to_string())
+}
+
+fn parse_pid(pid: &str) -> anyhow::Result<ProcessId> {
+    pid.parse()
+        .map_err(|_| anyhow::anyhow!("Invalid process id: {}", pid))
+}
+
+async fn run_runtime_command(
+    runtime_data: Arc<Mutex<RuntimeData>>,
+    command: anyhow::Result<RuntimeCommand>,
+    mut emitter: EventEmitter,
+) -> Result<ProcessId, server::ErrorResponse> {
+    // runtime commands are not guest processes; use ids the guest will never assign
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1 << 63);
+
+    let command = command.map_err(|e| server::ErrorResponse::msg(e.to_string()))?;
+    match command {
+        RuntimeCommand::PutInput { pid, data } => put_input(runtime_data, pid, &data).await?,
+        RuntimeCommand::CloseInput { pid } => put_input(runtime_data, pid, &[]).await?,
+        RuntimeCommand::SyncFs => sync_fs(runtime_data).await?,
+    }
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    tokio::spawn(async move {
+        emitter
+            .emit(server::ProcessStatus {
+                pid: id,
+                running: false,
+                return_code: 0,
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            })
+            .await;
+    });
+    Ok(id)
+}
+
+async fn sync_fs(runtime_data: Arc<Mutex<RuntimeData>>) -> Result<(), server::ErrorResponse> {
+    log::debug!("got sync fs");
+    let ga = runtime_data
+        .lock()
+        .await
         .ga()
-        .unwrap()
+        .map_err(|e| server::ErrorResponse::msg(e.to_string()))?;
+    let result = ga.sync_fs().await;
+    convert_result(result, "Syncing filesystems")
+}
+
+async fn put_input(
+    runtime_data: Arc<Mutex<RuntimeData>>,
+    pid: ProcessId,
+    data: &[u8],
+) -> Result<(), server::ErrorResponse> {
+    log::debug!("got input for process {}: {} B", pid, data.len());
+    let ga = runtime_data
         .lock()
         .await
-        .run_process(
-            &run.bin,
-            run.args
-                .iter()
-                .map(|s| s.as_ref())
-                .collect::<Vec<&str>>()
-                .as_slice(),
-            Some(&env[..]),
-            uid,
-            gid,
-            &[
-                None,
-                Some(RedirectFdType::RedirectFdPipeCyclic(0x1000)),
-                Some(RedirectFdType::RedirectFdPipeCyclic(0x1000)),
-            ],
-            Some(cwd),
-        )
-        .await;
+        .ga()
+        .map_err(|e| server::ErrorResponse::msg(e.to_string()))?;
 
-    Ok(convert_result(result, "Running process")?)
+    // an empty chunk closes the input
+    let chunks = match data.is_empty() {
+        true => vec![data],
+        false => data.chunks(STDIN_BUFFER_SIZE).collect(),
+    };
+    for chunk in chunks {
+        let deadline = time::Instant::now() + ga.timeouts().put_input;
+        loop {
+            let result = ga.put_input(pid, chunk).await;
+            match result {
+                // the stdin buffer is full; wait for the process to consume its input
+                Err(ref err) if err.errno() == Some(libc::EAGAIN as u32) => {
+                    if time::Instant::now() >= deadline {
+                        return Err(server::ErrorResponse::msg(format!(
+                            "Writing input of process {} timed out, the process does not read it",
+                            pid
+                        )));
+                    }
+                    time::sleep(STDIN_RETRY_INTERVAL).await
+                }
+                result => {
+                    convert_result(result, &format!("Writing input of process {}", pid))?;
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
 }
 
 async fn kill_command(
     runtime_data: Arc<Mutex<RuntimeData>>,
     kill: server::KillProcess,
+    grace_period: Duration,
 ) -> Result<(), server::ErrorResponse> {
     log::debug!("got kill: {:?}", kill);
-    // TODO: send signal
-    let data = runtime_data.lock().await;
-    let mutex = data.ga().unwrap();
-    let result = mutex.lock().await.kill(kill.pid).await;
+    // without an explicit signal, ask the process to terminate and kill it
+    // if it does not exit within the grace period
+    let (signal, grace_period) = match kill.signal {
+        0 => (SIGTERM, Some(grace_period)),
+        signal => (signal as u32, None),
+    };
+    let ga = runtime_data.lock().await.ga().unwrap();
+    let result = ga.kill(kill.pid, signal, grace_period).await;
     convert_result(result, &format!("Killing process {}", kill.pid))?;
     Ok(())
 }
 
-async fn stop(runtime_data: Arc<Mutex<RuntimeData>>) -> Result<(), server::ErrorResponse> {
+/// Syncs filesystems and powers the VM off. If that does not finish within
+/// `timeout`, QEMU is quit over QMP and eventually killed.
+async fn stop(
+    runtime_data: Arc<Mutex<RuntimeData>>,
+    timeout: Duration,
+) -> Result<(), server::ErrorResponse> {
     log::debug!("got shutdown");
-    let mut data = runtime_data.lock().await;
-    let mut runtime = data.runtime().unwrap();
+    // the whole sequence is bounded, escalating to terminating QEMU
+    let deadline = time::Instant::now() + timeout;
+    let (mut runtime, ga, qmp) = {
+        let mut data = runtime_data.lock().await;
+        let runtime = data
+            .runtime()
+            .map_err(|e| server::ErrorResponse::msg(e.to_string()))?;
+        (runtime, data.ga().ok(), data.qmp.clone())
+    };
+    runtime.set_stopping();
 
-    {
-        let mutex = data.ga().unwrap();
-        let mut ga = mutex.lock().await;
-        convert_result(ga.quit().await, "Sending quit")?;
+    if let Some(ga) = ga {
+        // make sure outputs written to volumes reach the host before powering off
+        stop_step(deadline, ga.sync_fs(), "Syncing filesystems").await;
+        stop_step(deadline, ga.quit(), "Sending quit").await;
     }
 
-    runtime
-        .wait()
-        .await
-        .expect("Waiting for runtime stop failed");
-    Ok(())
+    let remaining = deadline.saturating_duration_since(time::Instant::now());
+    match runtime.shutdown(qmp.as_deref(), remaining).await {
+        Ok(status) => {
+            log::debug!("VM exited: {}", status);
+            Ok(())
+        }
+        Err(err) => Err(server::ErrorResponse::msg(format!(
+            "Stopping the VM failed: {}",
+            err
+        ))),
+    }
 }
 
-fn offer() -> anyhow::Result<Option<serde_json::Value>> {
-    let gpu = GpuInfo::try_new()?;
+/// Runs a step of stopping the VM, which is abandoned once the `deadline` passes
+async fn stop_step<F>(deadline: time::Instant, step: F, msg: &str)
+where
+    F: Future<Output = RemoteCommandResult<()>>,
+{
+    match time::timeout_at(deadline, step).await {
+        Ok(result) => {
+            if let Err(err) = convert_result(result, msg) {
+                log::warn!("{}", err.message);
+            }
+        }
+        Err(_) => log::warn!("{} timed out", msg),
+    }
+}
+
+fn offer(work_dir: &Path, gpus: &[PciAddress]) -> anyhow::Result<Option<serde_json::Value>> {
+    let host = HostInfo::try_new(work_dir)?;
+    if !host.kvm {
+        log::warn!("KVM is not available, the runtime will not be able to start VMs");
+    }
+
+    let gpus = gpus
+        .iter()
+        .filter_map(|address| match GpuInfo::try_new(*address) {
+            // only devices bound to vfio-pci can be passed through to the VM
+            Ok(gpu) if gpu.is_vfio_bound() => Some(gpu),
+            Ok(_) => {
+                log::warn!("Skipping GPU {}: not bound to vfio-pci", address);
+                None
+            }
+            Err(err) => {
+                log::warn!("Skipping GPU: {}", err);
+                None
+            }
+        })
+        .collect::<Vec<_>>();
     let cpu = CpuInfo::try_new()?;
-    let model = format!(
-        "Stepping {} Family {} Model {}",
-        cpu.model.stepping, cpu.model.family, cpu.model.model
-    );
 
-    let mut capabilities = vec!["inet", "vpn", "manifest-support", "start-entrypoint"];
-    let cuda_cap;
+    let mut capabilities = vec![
+        "inet",
+        "vpn",
+        "manifest-support",
+        "start-entrypoint",
+        "interactive",
+    ];
+    let cuda_caps = gpus
+        .iter()
+        .filter(|gpu| gpu.is_nvidia())
+        .map(|gpu| format!("cuda, {}", gpu.name))
+        .collect::<Vec<_>>();
 
-    if gpu.name != "None" {
-        cuda_cap = format!("cuda, {}", gpu.name);
-        capabilities.push(&cuda_cap);
+    if !cuda_caps.is_empty() {
+        capabilities.extend(cuda_caps.iter().map(String::as_str));
         capabilities.push(&"cuda");
+    }
+    if !gpus.is_empty() {
         capabilities.push(&"gpu");
     }
 
+    let mut properties = serde_json::json!({
+        "golem.inf.cpu.vendor": cpu.model.vendor,
+        "golem.inf.cpu.brand": cpu.model.brand,
+        "golem.inf.cpu.model": cpu.model.description,
+        "golem.inf.cpu.architecture": std::env::consts::ARCH,
+        "golem.inf.cpu.capabilities": cpu.capabilities,
+        "golem.inf.host.cpu.threads": host.threads,
+        "golem.inf.host.mem.gib": host.memory as f64 / GIB,
+        "golem.inf.host.storage.gib": host.storage as f64 / GIB,
+        "golem.inf.kvm": host.kvm,
+        "golem.runtime.capabilities": capabilities
+    });
+    if let Some(threads_per_core) = cpu.topology.threads_per_core {
+        properties["golem.inf.cpu.threads-per-core"] = threads_per_core.into();
+    }
+    if let Some(cores_per_socket) = cpu.topology.cores_per_socket {
+        properties["golem.inf.cpu.cores-per-socket"] = cores_per_socket.into();
+    }
+    for cache in cpu.topology.caches.iter() {
+        let key = format!("golem.inf.cpu.cache.{}.kib", cache.name());
+        properties[key] = (cache.size / 1024).into();
+    }
+    if !gpus.is_empty() {
+        merge_properties(&mut properties, gpu_properties(&gpus));
+    }
+
     Ok(Some(serde_json::json!({
-        "properties": {
-            "golem.inf.cpu.vendor": cpu.model.vendor,
-            "golem.inf.cpu.brand": cpu.model.brand,
-            "golem.inf.cpu.model": model,
-            "golem.inf.cpu.capabilities": cpu.capabilities,
-            "golem.runtime.capabilities": capabilities
-        },
-        "constraints": ""
+        "properties": properties,
+        "constraints": host.constraints()
     })))
 }
 
+/// GPU offer properties; per-device values are listed in the same order
+fn gpu_properties(gpus: &[GpuInfo]) -> serde_json::Value {
+    let list = |f: fn(&GpuInfo) -> serde_json::Value| gpus.iter().map(f).collect::<Vec<_>>();
+
+    serde_json::json!({
+        "golem.inf.gpu.count": gpus.len(),
+        "golem.inf.gpu.model": list(|gpu| gpu.name.clone().into()),
+        "golem.inf.gpu.vendor": list(|gpu| gpu.vendor.clone().into()),
+        "golem.inf.gpu.vendor-id": list(|gpu| format!("{:04x}", gpu.device.vendor_id).into()),
+        "golem.inf.gpu.device-id": list(|gpu| format!("{:04x}", gpu.device.device_id).into()),
+        "golem.inf.gpu.address": list(|gpu| gpu.device.address.to_string().into()),
+        "golem.inf.gpu.memory.gib": list(|gpu| (gpu.memory as f64 / GIB).into()),
+        "golem.inf.gpu.driver": list(|gpu| gpu.device.driver.clone().into()),
+        "golem.inf.gpu.iommu-group": list(|gpu| gpu.device.iommu_group.into()),
+    })
+}
+
+fn merge_properties(properties: &mut serde_json::Value, other: serde_json::Value) {
+    if let (Some(properties), serde_json::Value::Object(other)) =
+        (properties.as_object_mut(), other)
+    {
+        properties.extend(other);
+    }
+}
+
 async fn test() -> anyhow::Result<()> {
     server::run_async(|e| async {
-        let ctx = Context::try_new().expect("Failed to initialize context");
+        let ctx: Context<Runtime> = Context::try_new().expect("Failed to initialize context");
         let task_package = runtime_dir()
             .expect("Runtime directory not found")
             .join(FILE_TEST_IMAGE)
@@ -359,6 +763,8 @@ async fn test() -> anyhow::Result<()> {
             deployment: Some(Deployment {
                 cpu_cores: 1,
                 mem_mib: 128,
+                // no scratch disk is created for the self-test
+                overlay: OverlayUpper::Tmpfs { size_mib: 64 },
                 task_package,
                 ..Default::default()
             }),
@@ -374,7 +780,8 @@ async fn test() -> anyhow::Result<()> {
             .expect("Failed to start runtime");
 
         println!("Stopping runtime");
-        stop(runtime.data.clone())
+        let timeout = Duration::from_secs(ctx.cli.runtime.stop_timeout);
+        stop(runtime.data.clone(), timeout)
             .await
             .expect("Failed to stop runtime");
 
@@ -413,8 +820,9 @@ async fn join_network(
     .cloned()
     .expect("No network endpoint");
 
-    let mutex = data.ga().unwrap();
-    let mut ga = mutex.lock().await;
+    let ga = data.ga().unwrap();
+    drop(data);
+
     convert_result(ga.add_hosts(hosts.iter()).await, "Updating network hosts")?;
 
     for net in networks {
@@ -453,20 +861,10 @@ async fn normalize_path<P: AsRef<Path>>(path: P) -> anyhow::Result<PathBuf> {
 }
 
 fn convert_result<T>(
-    result: io::Result<RemoteCommandResult<T>>,
+    result: RemoteCommandResult<T>,
     msg: &str,
 ) -> Result<T, server::ErrorResponse> {
-    match result {
-        Ok(Ok(result)) => Ok(result),
-        Ok(Err(exit_code)) => Err(server::ErrorResponse::msg(format!(
-            "{} failed, exit code: {}",
-            msg, exit_code
-        ))),
-        Err(error) => Err(server::ErrorResponse::msg(format!(
-            "{} failed: {}",
-            msg, error
-        ))),
-    }
+    result.map_err(|error| server::ErrorResponse::msg(format!("{} failed: {}", msg, error)))
 }
 
 fn extract_entrypoint(config: &ContainerConfig) -> Option<Vec<String>> {
@@ -505,6 +903,7 @@ async fn run_entrypoint(
             args,
             ..Default::default()
         },
+        true,
     )
     .await
     .map(|pid| {
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::guest_agent_comm::{GuestAgent, RedirectFdType, RemoteCommandResult};
+
+/// Maximum number of bytes requested from the guest at once
+pub const OUTPUT_CHUNK_SIZE: u64 = 0x10000;
+/// Guest pipe buffers are allocated in whole pages
+const OUTPUT_BUFFER_ALIGN: u64 = 0x1000;
+const DEFAULT_OUTPUT_BUFFER_SIZE: u64 = 0x1000;
+
+/// Redirection of a process output stream in the guest
+#[derive(Clone, Debug, PartialEq)]
+pub enum OutputMode {
+    /// Write to a file, e.g. one placed on a volume
+    File(String),
+    /// Buffer in a pipe of the given size, blocking the process while full
+    Blocking(u64),
+    /// Buffer in a pipe of the given size, dropping the oldest data when full
+    Cyclic(u64),
+}
+
+impl OutputMode {
+    pub fn redirect(&self) -> RedirectFdType<'_> {
+        match self {
+            OutputMode::File(path) => RedirectFdType::RedirectFdFile(path.as_bytes()),
+            OutputMode::Blocking(size) => RedirectFdType::RedirectFdPipeBlocking(*size),
+            OutputMode::Cyclic(size) => RedirectFdType::RedirectFdPipeCyclic(*size),
+        }
+    }
+}
+
+impl Default for OutputMode {
+    fn default() -> Self {
+        OutputMode::Cyclic(DEFAULT_OUTPUT_BUFFER_SIZE)
+    }
+}
+
+/// Parses `file:<path>`, `blocking:<size>` and `cyclic:<size>`
+impl FromStr for OutputMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mode, value) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Invalid output mode: {}", s))?;
+
+        let buffer_size = || -> anyhow::Result<u64> {
+            let size = value
+                .parse::<u64>()
+                .map_err(|_| anyhow::anyhow!("Invalid output buffer size: {}", value))?;
+            if size == 0 || size % OUTPUT_BUFFER_ALIGN != 0 {
+                anyhow::bail!(
+                    "Output buffer size must be a non-zero multiple of {}",
+                    OUTPUT_BUFFER_ALIGN
+                );
+            }
+            Ok(size)
+        };
+
+        Ok(match mode {
+            "file" if value.starts_with('/') => OutputMode::File(value.to_string()),
+            "file" => anyhow::bail!("Output file path must be absolute: {}", value),
+            "blocking" => OutputMode::Blocking(buffer_size()?),
+            "cyclic" => OutputMode::Cyclic(buffer_size()?),
+            _ => anyhow::bail!("Unknown output mode: {}", mode),
+        })
+    }
+}
+
+/// Output read from a single process' file descriptor
+#[derive(Debug)]
+pub struct OutputRead {
+    /// Number of bytes dropped by the guest since the previous read
+    pub dropped: u64,
+    pub data: Vec<u8>,
+    /// Whether more output may be available right away
+    pub more: bool,
+}
+
+/// Reads process output incrementally, tracking the redirection and the stream
+/// offset of each (process, fd) pair.
+///
+/// Output is pulled in chunks of at most `OUTPUT_CHUNK_SIZE` bytes. Callers are
+/// expected to consume each chunk before requesting the next one, so that a slow
+/// consumer holds data back in the guest instead of buffering it on the host.
+#[derive(Default)]
+pub struct OutputReader {
+    modes: Mutex<HashMap<(u64, u8), OutputMode>>,
+    offsets: Mutex<HashMap<(u64, u8), u64>>,
+}
+
+impl OutputReader {
+    pub async fn read(&self, ga: &GuestAgent, id: u64, fd: u8) -> RemoteCommandResult<OutputRead> {
+        let offset = self.offset(id, fd);
+        let chunk = ga.query_output(id, fd, offset, OUTPUT_CHUNK_SIZE).await?;
+
+        let end = chunk.offset + chunk.data.len() as u64;
+        self.offsets.lock().unwrap().insert((id, fd), end);
+
+        Ok(OutputRead {
+            dropped: chunk.offset.saturating_sub(offset),
+            more: chunk.data.len() as u64 == OUTPUT_CHUNK_SIZE,
+            data: chunk.data,
+        })
+    }
+
+    /// Records the redirection of a spawned process' output stream
+    pub fn register(&self, id: u64, fd: u8, mode: &OutputMode) {
+        self.modes.lock().unwrap().insert((id, fd), mode.clone());
+    }
+
+    /// Output streams of an exited process which may still hold buffered output.
+    /// Output redirected to a file can't be read back.
+    pub fn drained_fds(&self, id: u64) -> Vec<u8> {
+        let modes = self.modes.lock().unwrap();
+        [1, 2]
+            .iter()
+            .copied()
+            .filter(|fd| !matches!(modes.get(&(id, *fd)), Some(OutputMode::File(_))))
+            .collect()
+    }
+
+    /// Forgets the redirections and offsets of a process, once its output has been drained
+    pub fn remove(&self, id: u64) {
+        let mut modes = self.modes.lock().unwrap();
+        modes.retain(|(pid, _), _| *pid != id);
+        let mut offsets = self.offsets.lock().unwrap();
+        offsets.retain(|(pid, _), _| *pid != id);
+    }
+
+    fn offset(&self, id: u64, fd: u8) -> u64 {
+        let offsets = self.offsets.lock().unwrap();
+        offsets.get(&(id, fd)).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_output_mode() {
+        assert_eq!(
+            "file:/golem/output/log".parse::<OutputMode>().unwrap(),
+            OutputMode::File("/golem/output/log".to_string())
+        );
+        assert_eq!(
+            "blocking:8192".parse::<OutputMode>().unwrap(),
+            OutputMode::Blocking(8192)
+        );
+        assert_eq!(
+            "cyclic:4096".parse::<OutputMode>().unwrap(),
+            OutputMode::Cyclic(4096)
+        );
+    }
+
+    #[test]
+    fn parse_invalid_output_mode() {
+        for mode in [
+            "",
+            "cyclic",
+            "cyclic:",
+            "cyclic:0",
+            "cyclic:1000",
+            "blocking:-4096",
+            "blocking:4k",
+            "file:",
+            "file:relative/path",
+            "pipe:4096",
+        ] {
+            assert!(mode.parse::<OutputMode>().is_err(), "{}", mode);
+        }
+    }
+
+    #[test]
+    fn drain_piped_output_only() {
+        let output = OutputReader::default();
+        output.register(1, 1, &OutputMode::File("/golem/output/log".to_string()));
+        output.register(1, 2, &OutputMode::Cyclic(4096));
+        output.register(2, 1, &OutputMode::File("/golem/output/out".to_string()));
+        output.register(2, 2, &OutputMode::File("/golem/output/err".to_string()));
+        output.register(3, 1, &OutputMode::Blocking(4096));
+        output.register(3, 2, &OutputMode::Cyclic(4096));
+
+        assert_eq!(output.drained_fds(1), vec![2]);
+        assert!(output.drained_fds(2).is_empty());
+        assert_eq!(output.drained_fds(3), vec![1, 2]);
+
+        output.remove(2);
+        assert!(output.modes.lock().unwrap().keys().all(|(id, _)| *id != 2));
+    }
+}
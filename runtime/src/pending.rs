@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use futures::channel::oneshot;
+
+/// Requests awaiting a response over a multiplexed connection, keyed by request id.
+///
+/// Shared between the senders of requests and the task reading responses,
+/// which passes them on with `complete` and calls `close` once the connection is lost.
+pub(crate) struct PendingRequests<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+struct Inner<T> {
+    senders: HashMap<u64, oneshot::Sender<T>>,
+    /// Set once the connection is lost
+    closed: Option<(io::ErrorKind, String)>,
+}
+
+impl<T> Clone for PendingRequests<T> {
+    fn clone(&self) -> Self {
+        PendingRequests {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Default for PendingRequests<T> {
+    fn default() -> Self {
+        PendingRequests {
+            inner: Arc::new(Mutex::new(Inner {
+                senders: HashMap::new(),
+                closed: None,
+            })),
+        }
+    }
+}
+
+impl<T> PendingRequests<T> {
+    /// Registers a request. Has to be called before sending it, the response
+    /// may arrive before the write returns.
+    pub fn register(&self, id: u64) -> io::Result<PendingRequest<'_, T>> {
+        let (tx, rx) = oneshot::channel();
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(err) = inner.error() {
+            return Err(err);
+        }
+        inner.senders.insert(id, tx);
+        Ok(PendingRequest {
+            pending: self,
+            id,
+            rx,
+        })
+    }
+
+    /// Passes the response to the request awaiting it. The response is handed
+    /// back if no request with this id is awaiting one.
+    pub fn complete(&self, id: u64, response: T) -> Result<(), T> {
+        let sender = self.inner.lock().unwrap().senders.remove(&id);
+        match sender {
+            Some(sender) => sender.send(response),
+            None => Err(response),
+        }
+    }
+
+    /// Fails all requests in flight with `err` and rejects any further ones
+    pub fn close(&self, err: &io::Error) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.closed.replace((err.kind(), err.to_string()));
+        // dropping the senders fails the requests
+        inner.senders.clear();
+    }
+
+    /// Reason of closing the connection, if it's closed
+    pub fn closed(&self) -> Option<io::Error> {
+        self.inner.lock().unwrap().error()
+    }
+
+    fn unregister(&self, id: u64) {
+        self.inner.lock().unwrap().senders.remove(&id);
+    }
+}
+
+impl<T> Inner<T> {
+    fn error(&self) -> Option<io::Error> {
+        self.closed
+            .as_ref()
+            .map(|(kind, msg)| io::Error::new(*kind, msg.clone()))
+    }
+}
+
+/// Registered request, unregistered once its response is received or
+/// awaiting it is abandoned
+pub(crate) struct PendingRequest<'a, T> {
+    pending: &'a PendingRequests<T>,
+    id: u64,
+    rx: oneshot::Receiver<T>,
+}
+
+impl<T> PendingRequest<'_, T> {
+    /// Waits for the response, fails once the connection is closed
+    pub async fn response(&mut self) -> io::Result<T> {
+        match (&mut self.rx).await {
+            Ok(response) => Ok(response),
+            Err(oneshot::Canceled) => {
+                let closed = || io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed");
+                Err(self.pending.closed().unwrap_or_else(closed))
+            }
+        }
+    }
+}
+
+impl<T> Drop for PendingRequest<'_, T> {
+    fn drop(&mut self) {
+        self.pending.unregister(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn complete_request() {
+        let pending = PendingRequests::default();
+        let mut request = pending.register(1).unwrap();
+        assert_eq!(pending.complete(1, "ok"), Ok(()));
+        assert_eq!(block_on(request.response()).unwrap(), "ok");
+        assert_eq!(pending.complete(1, "late"), Err("late"));
+    }
+
+    #[test]
+    fn abandoned_request() {
+        let pending = PendingRequests::default();
+        drop(pending.register(1).unwrap());
+        assert!(pending.inner.lock().unwrap().senders.is_empty());
+        // a late response is handed back to the caller of `complete`
+        assert_eq!(pending.complete(1, "late"), Err("late"));
+    }
+
+    #[tokio::test]
+    async fn timed_out_request() {
+        let pending = PendingRequests::<&str>::default();
+        let response = async {
+            let mut request = pending.register(1).unwrap();
+            request.response().await
+        };
+        let timeout = std::time::Duration::from_millis(1);
+        assert!(tokio::time::timeout(timeout, response).await.is_err());
+
+        assert!(pending.inner.lock().unwrap().senders.is_empty());
+        assert_eq!(pending.complete(1, "late"), Err("late"));
+    }
+
+    #[test]
+    fn closed_connection() {
+        let pending = PendingRequests::<&str>::default();
+        let mut request = pending.register(1).unwrap();
+        pending.close(&io::Error::new(io::ErrorKind::UnexpectedEof, "eof"));
+
+        let err = block_on(request.response()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(err.to_string(), "eof");
+        assert!(pending.register(2).is_err());
+    }
+}
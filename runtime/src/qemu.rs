@@ -0,0 +1,383 @@
+use std::ffi::OsStr;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use tokio::process;
+
+use crate::detect_pci::PciAddress;
+
+/// Model of a QEMU virtual machine, rendered into command line arguments
+#[derive(Clone, Debug, Default)]
+pub struct VmConfig {
+    /// Machine type and its properties, e.g. `virt,gic-version=host`
+    pub machine: Option<String>,
+    pub kvm: bool,
+    /// CPU model, e.g. `host`
+    pub cpu: Option<String>,
+    pub cpus: usize,
+    pub memory_mib: usize,
+    pub kernel: PathBuf,
+    pub initrd: PathBuf,
+    pub kernel_args: Vec<String>,
+    /// VGA card type; `None` keeps the QEMU default
+    pub vga: Option<String>,
+    /// Exit instead of rebooting the guest
+    pub no_reboot: bool,
+    pub chardevs: Vec<Chardev>,
+    pub drives: Vec<Drive>,
+    pub netdevs: Vec<Netdev>,
+    /// Devices, in the order of creation
+    pub devices: Vec<Device>,
+    pub virtfs: Vec<Virtfs>,
+    /// Unix socket of the QMP control channel
+    pub qmp: Option<PathBuf>,
+}
+
+impl VmConfig {
+    pub fn chardev(&mut self, chardev: Chardev) -> &mut Self {
+        self.chardevs.push(chardev);
+        self
+    }
+
+    pub fn drive(&mut self, drive: Drive) -> &mut Self {
+        self.drives.push(drive);
+        self
+    }
+
+    pub fn netdev(&mut self, netdev: Netdev) -> &mut Self {
+        self.netdevs.push(netdev);
+        self
+    }
+
+    pub fn device(&mut self, device: Device) -> &mut Self {
+        self.devices.push(device);
+        self
+    }
+
+    pub fn virtfs(&mut self, virtfs: Virtfs) -> &mut Self {
+        self.virtfs.push(virtfs);
+        self
+    }
+
+    /// Renders the command line arguments. Devices of each kind keep their
+    /// order, which determines e.g. guest drive names and serial port numbers.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut arg = |name: &str, value: String| {
+            args.push(name.to_string());
+            args.push(value);
+        };
+
+        if let Some(machine) = &self.machine {
+            arg("-machine", machine.clone());
+        }
+        if let Some(cpu) = &self.cpu {
+            arg("-cpu", cpu.clone());
+        }
+        arg("-m", format!("{}M", self.memory_mib));
+        arg("-smp", self.cpus.to_string());
+        arg("-kernel", self.kernel.display().to_string());
+        arg("-initrd", self.initrd.display().to_string());
+        arg("-append", self.kernel_args.join(" "));
+        if let Some(vga) = &self.vga {
+            arg("-vga", vga.clone());
+        }
+        for chardev in self.chardevs.iter() {
+            arg("-chardev", chardev.to_string());
+        }
+        for drive in self.drives.iter() {
+            arg("-drive", drive.to_string());
+        }
+        if self.netdevs.is_empty() {
+            arg("-net", "none".to_string());
+        }
+        for netdev in self.netdevs.iter() {
+            arg("-netdev", netdev.to_string());
+        }
+        for device in self.devices.iter() {
+            arg("-device", device.to_string());
+        }
+        for virtfs in self.virtfs.iter() {
+            arg("-virtfs", virtfs.to_string());
+        }
+
+        if let Some(qmp) = &self.qmp {
+            arg("-qmp", format!("unix:{},server=on,wait=off", qmp.display()));
+        }
+
+        if self.kvm {
+            args.push("-enable-kvm".to_string());
+        }
+        if self.no_reboot {
+            args.push("-no-reboot".to_string());
+        }
+        args.push("-nographic".to_string());
+        args
+    }
+
+    pub fn command(&self, program: impl AsRef<OsStr>) -> process::Command {
+        let mut cmd = process::Command::new(program);
+        cmd.args(self.args());
+        cmd
+    }
+}
+
+/// Unix socket character device, listening for a single connection
+#[derive(Clone, Debug)]
+pub struct Chardev {
+    pub id: String,
+    pub path: PathBuf,
+}
+
+impl fmt::Display for Chardev {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "socket,path={},server=on,wait=off,id={}",
+            self.path.display(),
+            self.id
+        )
+    }
+}
+
+/// Raw disk image attached via virtio-blk
+#[derive(Clone, Debug)]
+pub struct Drive {
+    pub file: PathBuf,
+    pub readonly: bool,
+}
+
+impl fmt::Display for Drive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "file={},cache=unsafe", self.file.display())?;
+        if self.readonly {
+            write!(f, ",readonly=on")?;
+        }
+        write!(f, ",format=raw,if=virtio")
+    }
+}
+
+/// Socket network backend
+#[derive(Clone, Debug)]
+pub struct Netdev {
+    pub id: String,
+    pub socket: NetdevSocket,
+}
+
+#[derive(Clone, Debug)]
+pub enum NetdevSocket {
+    Udp {
+        remote: SocketAddr,
+        local: SocketAddr,
+    },
+    Connect(SocketAddr),
+    Listen(SocketAddr),
+}
+
+impl fmt::Display for Netdev {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let id = &self.id;
+        match &self.socket {
+            NetdevSocket::Udp { remote, local } => {
+                write!(f, "socket,id={id},udp={remote},localaddr={local}")
+            }
+            NetdevSocket::Connect(remote) => write!(f, "socket,id={id},connect={remote}"),
+            NetdevSocket::Listen(local) => write!(f, "socket,id={id},listen={local}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Device {
+    VirtioSerial,
+    VirtioRng,
+    VirtioBalloon,
+    VirtSerialPort { chardev: String, name: String },
+    VirtioNet { netdev: String, mac: String },
+    VfioPci { host: PciAddress },
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Device::VirtioSerial => write!(f, "virtio-serial"),
+            Device::VirtioRng => write!(f, "virtio-rng-pci"),
+            Device::VirtioBalloon => write!(f, "virtio-balloon-pci"),
+            Device::VirtSerialPort { chardev, name } => {
+                write!(f, "virtserialport,chardev={chardev},name={name}")
+            }
+            Device::VirtioNet { netdev, mac } => {
+                write!(f, "virtio-net-pci,netdev={netdev},mac={mac}")
+            }
+            Device::VfioPci { host } => write!(f, "vfio-pci,host={host}"),
+        }
+    }
+}
+
+/// Host directory shared over 9p
+#[derive(Clone, Debug)]
+pub struct Virtfs {
+    pub tag: String,
+    pub path: PathBuf,
+}
+
+impl fmt::Display for Virtfs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "local,id={tag},path={path},security_model=none,mount_tag={tag}",
+            tag = self.tag,
+            path = self.path.to_string_lossy(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn minimal_args() {
+        let config = VmConfig {
+            cpus: 1,
+            memory_mib: 256,
+            kernel: "vmlinuz-virt".into(),
+            initrd: "initramfs.cpio.gz".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.args(),
+            strings(&[
+                "-m",
+                "256M",
+                "-smp",
+                "1",
+                "-kernel",
+                "vmlinuz-virt",
+                "-initrd",
+                "initramfs.cpio.gz",
+                "-append",
+                "",
+                "-net",
+                "none",
+                "-nographic",
+            ])
+        );
+    }
+
+    #[test]
+    fn full_args() {
+        let mut config = VmConfig {
+            machine: Some("q35".to_string()),
+            kvm: true,
+            cpu: Some("host".to_string()),
+            cpus: 4,
+            memory_mib: 2048,
+            kernel: "/runtime/vmlinuz-virt".into(),
+            initrd: "/runtime/initramfs.cpio.gz".into(),
+            kernel_args: strings(&["console=ttyS0", "panic=1"]),
+            vga: Some("none".to_string()),
+            no_reboot: true,
+            qmp: Some("/tmp/qmp.sock".into()),
+            ..Default::default()
+        };
+        config
+            .device(Device::VirtioSerial)
+            .chardev(Chardev {
+                id: "manager_cdev".to_string(),
+                path: "/tmp/manager.sock".into(),
+            })
+            .device(Device::VirtSerialPort {
+                chardev: "manager_cdev".to_string(),
+                name: "manager_port".to_string(),
+            })
+            .drive(Drive {
+                file: "/images/task.gvmi".into(),
+                readonly: true,
+            })
+            .drive(Drive {
+                file: "/work/storage.img".into(),
+                readonly: false,
+            })
+            .netdev(Netdev {
+                id: "vpn".to_string(),
+                socket: NetdevSocket::Connect("127.0.0.1:5000".parse().unwrap()),
+            })
+            .device(Device::VirtioNet {
+                netdev: "vpn".to_string(),
+                mac: "90:13:00:00:00:01".to_string(),
+            })
+            .device(Device::VfioPci {
+                host: "0000:01:00.0".parse().unwrap(),
+            })
+            .virtfs(Virtfs {
+                tag: "mnt0".to_string(),
+                path: "/work/vol-0".into(),
+            });
+
+        assert_eq!(
+            config.args(),
+            strings(&[
+                "-machine",
+                "q35",
+                "-cpu",
+                "host",
+                "-m",
+                "2048M",
+                "-smp",
+                "4",
+                "-kernel",
+                "/runtime/vmlinuz-virt",
+                "-initrd",
+                "/runtime/initramfs.cpio.gz",
+                "-append",
+                "console=ttyS0 panic=1",
+                "-vga",
+                "none",
+                "-chardev",
+                "socket,path=/tmp/manager.sock,server=on,wait=off,id=manager_cdev",
+                "-drive",
+                "file=/images/task.gvmi,cache=unsafe,readonly=on,format=raw,if=virtio",
+                "-drive",
+                "file=/work/storage.img,cache=unsafe,format=raw,if=virtio",
+                "-netdev",
+                "socket,id=vpn,connect=127.0.0.1:5000",
+                "-device",
+                "virtio-serial",
+                "-device",
+                "virtserialport,chardev=manager_cdev,name=manager_port",
+                "-device",
+                "virtio-net-pci,netdev=vpn,mac=90:13:00:00:00:01",
+                "-device",
+                "vfio-pci,host=0000:01:00.0",
+                "-virtfs",
+                "local,id=mnt0,path=/work/vol-0,security_model=none,mount_tag=mnt0",
+                "-qmp",
+                "unix:/tmp/qmp.sock,server=on,wait=off",
+                "-enable-kvm",
+                "-no-reboot",
+                "-nographic",
+            ])
+        );
+    }
+
+    #[test]
+    fn udp_netdev() {
+        let netdev = Netdev {
+            id: "inet".to_string(),
+            socket: NetdevSocket::Udp {
+                remote: "127.0.0.1:6000".parse().unwrap(),
+                local: "127.0.0.1:6001".parse().unwrap(),
+            },
+        };
+        assert_eq!(
+            netdev.to_string(),
+            "socket,id=inet,udp=127.0.0.1:6000,localaddr=127.0.0.1:6001"
+        );
+    }
+}
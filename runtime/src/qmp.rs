@@ -0,0 +1,311 @@
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::lock::Mutex;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines, ReadHalf, WriteHalf};
+use tokio::net::UnixStream;
+use tokio::sync::broadcast;
+use tokio::{spawn, time};
+
+use crate::pending::PendingRequests;
+
+const EVENT_CHANNEL_CAPACITY: usize = 16;
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+/// QEMU handles commands itself, without involving the guest
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type QmpResult<T> = Result<T, QmpError>;
+
+/// Error returned by QEMU in response to a command
+#[derive(Clone, Debug, Deserialize)]
+pub struct QmpError {
+    pub class: String,
+    pub desc: String,
+}
+
+impl std::fmt::Display for QmpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.class, self.desc)
+    }
+}
+
+/// Asynchronous event emitted by QEMU, e.g. `SHUTDOWN` or `GUEST_PANICKED`
+#[derive(Clone, Debug, Deserialize)]
+pub struct QmpEvent {
+    pub event: String,
+    #[serde(default)]
+    pub data: Value,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct StatusInfo {
+    pub running: bool,
+    /// Run state, e.g. `running`, `paused` or `shutdown`
+    pub status: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BalloonInfo {
+    /// Current guest memory size [B]
+    pub actual: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlockStats {
+    #[serde(default)]
+    pub device: String,
+    #[serde(rename = "node-name", default)]
+    pub node_name: String,
+    pub stats: BlockDeviceStats,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BlockDeviceStats {
+    pub rd_bytes: u64,
+    pub wr_bytes: u64,
+    pub rd_operations: u64,
+    pub wr_operations: u64,
+    pub flush_operations: u64,
+}
+
+/// Client of the QEMU Machine Protocol, controlling the VM independently of the guest
+pub struct QmpClient {
+    stream: Arc<Mutex<WriteHalf<UnixStream>>>,
+    last_cmd_id: AtomicU64,
+    /// Commands awaiting a response from QEMU, keyed by command id
+    pending: PendingRequests<QmpResult<Value>>,
+    events: broadcast::Sender<QmpEvent>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Message {
+    Return {
+        #[serde(rename = "return")]
+        value: Value,
+        id: Option<u64>,
+    },
+    Error {
+        error: QmpError,
+        id: Option<u64>,
+    },
+    Event(QmpEvent),
+    Greeting {
+        #[serde(rename = "QMP")]
+        _qmp: Value,
+    },
+}
+
+async fn reader(
+    mut lines: Lines<BufReader<ReadHalf<UnixStream>>>,
+    pending: PendingRequests<QmpResult<Value>>,
+    events: broadcast::Sender<QmpEvent>,
+) {
+    let err = loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => {
+                break io::Error::new(io::ErrorKind::UnexpectedEof, "QMP connection closed")
+            }
+            Err(err) => break err,
+        };
+
+        let (id, result) = match serde_json::from_str(&line) {
+            Ok(Message::Return { value, id }) => (id, Ok(value)),
+            Ok(Message::Error { error, id }) => (id, Err(error)),
+            Ok(Message::Event(event)) => {
+                log::debug!("QMP event: {}", event.event);
+                let _ = events.send(event);
+                continue;
+            }
+            Ok(Message::Greeting { .. }) => continue,
+            Err(err) => {
+                log::warn!("Invalid QMP message: {}: {}", err, line);
+                continue;
+            }
+        };
+
+        let unknown = match id {
+            Some(id) => pending.complete(id, result).is_err(),
+            None => true,
+        };
+        if unknown {
+            log::warn!("Got QMP response for unknown command: {:?}", id);
+        }
+    };
+
+    log::debug!("QMP connection closed: {}", err);
+    pending.close(&err);
+}
+
+impl QmpClient {
+    /// Connects to the QMP socket, waiting up to `timeout` for QEMU to create it,
+    /// and leaves the capabilities negotiation mode
+    pub async fn connected<P: AsRef<Path>>(
+        path: P,
+        timeout: Duration,
+    ) -> io::Result<Arc<QmpClient>> {
+        let deadline = time::Instant::now() + timeout;
+        let stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(err) => match err.kind() {
+                    io::ErrorKind::NotFound => {
+                        log::info!("Waiting for QMP socket ...");
+                        if time::Instant::now() >= deadline {
+                            return Err(io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "Could not connect to the QMP socket",
+                            ));
+                        }
+                        time::sleep(CONNECT_RETRY_INTERVAL).await;
+                    }
+                    _ => return Err(err),
+                },
+            }
+        };
+
+        let (stream_read, stream_write) = tokio::io::split(stream);
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let pending = PendingRequests::default();
+        let qmp = Arc::new(QmpClient {
+            stream: Arc::new(Mutex::new(stream_write)),
+            last_cmd_id: AtomicU64::new(0),
+            pending: pending.clone(),
+            events: events.clone(),
+        });
+        spawn(reader(BufReader::new(stream_read).lines(), pending, events));
+
+        qmp.execute("qmp_capabilities", None)
+            .await?
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        Ok(qmp)
+    }
+
+    /// Subscribes to events emitted after this call
+    pub fn subscribe(&self) -> broadcast::Receiver<QmpEvent> {
+        self.events.subscribe()
+    }
+
+    fn get_new_cmd_id(&self) -> u64 {
+        self.last_cmd_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Executes a command and waits up to `COMMAND_TIMEOUT` for its result.
+    ///
+    /// Dropping the returned future is safe: the command is either sent as a whole
+    /// or not at all.
+    pub async fn execute(
+        &self,
+        command: &str,
+        arguments: Option<Value>,
+    ) -> io::Result<QmpResult<Value>> {
+        let id = self.get_new_cmd_id();
+        let mut msg = json!({ "execute": command, "id": id });
+        if let Some(arguments) = arguments {
+            msg["arguments"] = arguments;
+        }
+        let mut msg = msg.to_string();
+        msg.push('\n');
+
+        // unregistered once the result is received or awaiting it is abandoned
+        let mut request = self.pending.register(id)?;
+
+        // writing in a separate task, a partially written command would corrupt the stream
+        let stream = self.stream.clone();
+        let write = spawn(async move { stream.lock().await.write_all(msg.as_bytes()).await });
+
+        let response = async move {
+            match write.await {
+                Ok(result) => result?,
+                Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+            }
+            request.response().await
+        };
+
+        match time::timeout(COMMAND_TIMEOUT, response).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "QMP command {} timed out after {:?}",
+                    command, COMMAND_TIMEOUT
+                ),
+            )),
+        }
+    }
+
+    async fn execute_as<T: DeserializeOwned>(
+        &self,
+        command: &str,
+        arguments: Option<Value>,
+    ) -> io::Result<QmpResult<T>> {
+        match self.execute(command, arguments).await? {
+            Ok(value) => serde_json::from_value(value)
+                .map(Ok)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(err) => Ok(Err(err)),
+        }
+    }
+
+    async fn execute_ok(
+        &self,
+        command: &str,
+        arguments: Option<Value>,
+    ) -> io::Result<QmpResult<()>> {
+        Ok(self.execute(command, arguments).await?.map(|_| ()))
+    }
+
+    pub async fn query_status(&self) -> io::Result<QmpResult<StatusInfo>> {
+        self.execute_as("query-status", None).await
+    }
+
+    /// Requests an ACPI shutdown of the guest
+    pub async fn system_powerdown(&self) -> io::Result<QmpResult<()>> {
+        self.execute_ok("system_powerdown", None).await
+    }
+
+    /// Terminates QEMU immediately
+    pub async fn quit(&self) -> io::Result<QmpResult<()>> {
+        self.execute_ok("quit", None).await
+    }
+
+    pub async fn query_balloon(&self) -> io::Result<QmpResult<BalloonInfo>> {
+        self.execute_as("query-balloon", None).await
+    }
+
+    /// Sets the target guest memory size [B]
+    pub async fn balloon(&self, value: u64) -> io::Result<QmpResult<()>> {
+        let arguments = json!({ "value": value });
+        self.execute_ok("balloon", Some(arguments)).await
+    }
+
+    /// Hot-plugs a device, `properties` are passed to the device driver
+    pub async fn device_add(
+        &self,
+        driver: &str,
+        id: &str,
+        properties: serde_json::Map<String, Value>,
+    ) -> io::Result<QmpResult<()>> {
+        let mut arguments = properties;
+        arguments.insert("driver".to_string(), driver.into());
+        arguments.insert("id".to_string(), id.into());
+        self.execute_ok("device_add", Some(arguments.into())).await
+    }
+
+    /// Requests the removal of a device; completion is signalled by a `DEVICE_DELETED` event
+    pub async fn device_del(&self, id: &str) -> io::Result<QmpResult<()>> {
+        let arguments = json!({ "id": id });
+        self.execute_ok("device_del", Some(arguments)).await
+    }
+
+    pub async fn query_blockstats(&self) -> io::Result<QmpResult<Vec<BlockStats>>> {
+        self.execute_as("query-blockstats", None).await
+    }
+}
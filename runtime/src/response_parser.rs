@@ -6,8 +6,16 @@ use tokio::io::{AsyncRead, AsyncReadExt};
 pub enum Response {
     Ok,
     OkU64(u64),
-    OkBytes(Vec<u8>),
     Err(u32),
+    OkOutput(OutputChunk),
+}
+
+/// Chunk of process output read from the guest
+#[derive(Debug)]
+pub struct OutputChunk {
+    /// Offset of `data` in the output stream
+    pub offset: u64,
+    pub data: Vec<u8>,
 }
 
 #[derive(Debug)]
@@ -39,6 +47,24 @@ pub struct ExitReason {
     pub type_: ExitType,
 }
 
+impl ExitReason {
+    /// Encodes the reason as a single return code.
+    ///
+    /// Exit statuses are returned as is. Processes terminated by a signal map to
+    /// the negated signal number, with the `0x80` bit set (as in `WCOREFLAG`) when
+    /// a core was dumped, e.g. `-9` for `SIGKILL` and `-139` for a dumped `SIGSEGV`.
+    pub fn return_code(&self) -> i32 {
+        const CORE_DUMP_FLAG: i32 = 0x80;
+
+        let status = self.status as i32;
+        match self.type_ {
+            ExitType::Exited => status,
+            ExitType::Killed => -status,
+            ExitType::Dumped => -(status | CORE_DUMP_FLAG),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Notification {
     OutputAvailable { id: u64, fd: u32 },
@@ -100,13 +126,6 @@ pub async fn parse_one_response<T: AsyncRead + Unpin>(
                 resp: Response::OkU64(val),
             }))
         }
-        2 => {
-            let buf = recv_bytes(stream).await?;
-            Ok(GuestAgentMessage::Response(ResponseWithId {
-                id: id,
-                resp: Response::OkBytes(buf),
-            }))
-        }
         3 => {
             let code = recv_u32(stream).await?;
             Ok(GuestAgentMessage::Response(ResponseWithId {
@@ -150,9 +169,45 @@ pub async fn parse_one_response<T: AsyncRead + Unpin>(
                 ))
             }
         }
+        6 => {
+            let offset = recv_u64(stream).await?;
+            let data = recv_bytes(stream).await?;
+            Ok(GuestAgentMessage::Response(ResponseWithId {
+                id: id,
+                resp: Response::OkOutput(OutputChunk { offset, data }),
+            }))
+        }
         _ => Err(io::Error::new(
             io::ErrorKind::InvalidData,
             "Invalid response type",
         )),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn return_code(status: u8, type_: ExitType) -> i32 {
+        ExitReason { status, type_ }.return_code()
+    }
+
+    #[test]
+    fn exited_return_code() {
+        assert_eq!(return_code(0, ExitType::Exited), 0);
+        assert_eq!(return_code(1, ExitType::Exited), 1);
+        assert_eq!(return_code(255, ExitType::Exited), 255);
+    }
+
+    #[test]
+    fn killed_return_code() {
+        assert_eq!(return_code(9, ExitType::Killed), -9);
+        assert_eq!(return_code(15, ExitType::Killed), -15);
+    }
+
+    #[test]
+    fn dumped_return_code() {
+        assert_eq!(return_code(11, ExitType::Dumped), -139);
+        assert_eq!(return_code(6, ExitType::Dumped), -134);
+    }
+}
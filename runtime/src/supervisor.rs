@@ -0,0 +1,323 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{oneshot, watch};
+use tokio::{process, spawn, time};
+
+use ya_runtime_sdk::EventEmitter;
+
+use crate::guest_agent_comm::GuestAgent;
+use crate::qmp::QmpClient;
+
+/// Number of serial console lines reported when the VM fails
+const CONSOLE_TAIL_LINES: usize = 40;
+/// Interval between guest agent pings
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// Ping response deadline. The guest handles requests sequentially, so a ping
+/// may wait for e.g. a filesystem sync to finish.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Number of consecutive failed pings after which the VM is considered unhealthy,
+/// until a ping succeeds again
+const HEARTBEAT_MAX_MISSED: usize = 3;
+/// Time given to QEMU to exit after a QMP `quit`
+const QMP_QUIT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time given to the VM process to exit after `SIGKILL`
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most recent lines written to the VM serial console
+#[derive(Default)]
+pub struct ConsoleLog {
+    lines: std::sync::Mutex<VecDeque<String>>,
+}
+
+impl ConsoleLog {
+    pub fn push(&self, line: String) {
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == CONSOLE_TAIL_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    pub fn tail(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Handle of a supervised VM process
+pub struct VmHandle {
+    stopping: Arc<AtomicBool>,
+    healthy: Arc<AtomicBool>,
+    kill: Option<oneshot::Sender<()>>,
+    exit: watch::Receiver<Option<ExitStatus>>,
+    sockets: Vec<PathBuf>,
+}
+
+impl VmHandle {
+    /// Whether the VM is running and the guest agent responds to pings.
+    /// An unresponsive VM becomes healthy again once a ping succeeds.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    /// Marks the shutdown as requested, so that the VM exit is not reported as a failure
+    pub fn set_stopping(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    /// Kills the VM process
+    pub fn kill(&mut self) {
+        self.set_stopping();
+        if let Some(kill) = self.kill.take() {
+            let _ = kill.send(());
+        }
+    }
+
+    /// Waits for the VM process to exit
+    pub async fn wait(&mut self) -> anyhow::Result<ExitStatus> {
+        loop {
+            if let Some(status) = *self.exit.borrow_and_update() {
+                return Ok(status);
+            }
+            if self.exit.changed().await.is_err() {
+                anyhow::bail!("VM supervisor terminated");
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for the VM to power off, which should already have been
+    /// requested from the guest. Then escalates to a QMP `quit` and finally to
+    /// killing the VM process. Sockets are removed in any case.
+    pub async fn shutdown(
+        &mut self,
+        qmp: Option<&QmpClient>,
+        timeout: Duration,
+    ) -> anyhow::Result<ExitStatus> {
+        self.set_stopping();
+        let result = self.escalate(qmp, timeout).await;
+        // normally done by the supervisor once the process exits
+        remove_sockets(&self.sockets);
+        result
+    }
+
+    async fn escalate(
+        &mut self,
+        qmp: Option<&QmpClient>,
+        timeout: Duration,
+    ) -> anyhow::Result<ExitStatus> {
+        if let Ok(result) = time::timeout(timeout, self.wait()).await {
+            return result;
+        }
+
+        if let Some(qmp) = qmp {
+            log::warn!("VM did not power off within {:?}, quitting QEMU", timeout);
+            match time::timeout(QMP_QUIT_TIMEOUT, qmp.quit()).await {
+                Ok(Ok(Ok(()))) => (),
+                Ok(Ok(Err(err))) => log::warn!("QMP quit failed: {}", err),
+                Ok(Err(err)) => log::warn!("QMP quit failed: {}", err),
+                Err(_) => log::warn!("QMP quit timed out"),
+            }
+            if let Ok(result) = time::timeout(QMP_QUIT_TIMEOUT, self.wait()).await {
+                return result;
+            }
+        }
+
+        log::warn!("Killing the VM process");
+        self.kill();
+        match time::timeout(KILL_TIMEOUT, self.wait()).await {
+            Ok(result) => result,
+            Err(_) => anyhow::bail!("VM process did not exit after being killed"),
+        }
+    }
+}
+
+/// Watches the VM process and the guest agent connection, which is periodically
+/// pinged. Unless `stopping` is set, i.e. the shutdown was requested or is expected,
+/// a failure is reported along with the tail of the serial console. Sockets are
+/// removed once the VM process exits.
+pub fn supervise(
+    child: process::Child,
+    ga: Arc<GuestAgent>,
+    console: Arc<ConsoleLog>,
+    sockets: Vec<PathBuf>,
+    stopping: Arc<AtomicBool>,
+    emitter: EventEmitter,
+) -> VmHandle {
+    let (kill_tx, kill_rx) = oneshot::channel();
+    let (exit_tx, exit_rx) = watch::channel(None);
+    let healthy = Arc::new(AtomicBool::new(true));
+
+    let handle = VmHandle {
+        stopping: stopping.clone(),
+        healthy: healthy.clone(),
+        kill: Some(kill_tx),
+        exit: exit_rx,
+        sockets: sockets.clone(),
+    };
+
+    spawn(async move {
+        let mut supervisor = Supervisor {
+            stopping,
+            healthy,
+            console,
+            emitter,
+            failed: false,
+        };
+        let status = supervisor.watch(child, ga, kill_rx).await;
+
+        remove_sockets(&sockets);
+        let _ = exit_tx.send(status);
+    });
+
+    handle
+}
+
+pub(crate) fn remove_sockets(sockets: &[PathBuf]) {
+    for socket in sockets {
+        if let Err(err) = std::fs::remove_file(socket) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                log::warn!("Unable to remove socket {}: {}", socket.display(), err);
+            }
+        }
+    }
+}
+
+struct Supervisor {
+    stopping: Arc<AtomicBool>,
+    healthy: Arc<AtomicBool>,
+    console: Arc<ConsoleLog>,
+    emitter: EventEmitter,
+    /// Set on the first failure, which is final
+    failed: bool,
+}
+
+impl Supervisor {
+    async fn watch(
+        &mut self,
+        mut child: process::Child,
+        ga: Arc<GuestAgent>,
+        mut kill_rx: oneshot::Receiver<()>,
+    ) -> Option<ExitStatus> {
+        let mut ga_reader = ga.take_reader();
+        let mut responsive = true;
+        let pings = heartbeat(ga.clone(), responsive);
+        tokio::pin!(pings);
+        let mut killed = false;
+
+        let status = loop {
+            tokio::select! {
+                status = child.wait() => break status,
+                _ = &mut kill_rx, if !killed => {
+                    // also reached when the handle is dropped
+                    killed = true;
+                    if let Err(err) = child.start_kill() {
+                        log::error!("Unable to kill the VM process: {}", err);
+                    }
+                }
+                _ = async { ga_reader.as_mut().unwrap().await }, if ga_reader.is_some() => {
+                    ga_reader = None;
+                    self.fail("Guest agent connection closed".to_string()).await;
+                }
+                result = &mut pings, if !self.failed && !self.stopping.load(Ordering::SeqCst) => {
+                    responsive = result.is_ok();
+                    match result {
+                        Ok(()) => self.responsive().await,
+                        Err(reason) => self.unresponsive(reason).await,
+                    }
+                    pings.set(heartbeat(ga.clone(), responsive));
+                }
+            }
+        };
+
+        match status {
+            Ok(status) => {
+                log::info!("VM process exited: {}", status);
+                // a guest kernel panic ends the process as well, due to `-no-reboot`
+                self.fail(format!("VM process exited: {}", status)).await;
+                Some(status)
+            }
+            Err(err) => {
+                self.fail(format!("Unable to wait for the VM process: {}", err))
+                    .await;
+                None
+            }
+        }
+    }
+
+    /// Marks the VM as unhealthy until it responds again
+    async fn unresponsive(&mut self, reason: String) {
+        self.healthy.store(false, Ordering::SeqCst);
+        log::warn!("VM unresponsive: {}", reason);
+        self.emitter
+            .state(
+                "vm",
+                Some(serde_json::json!({
+                    "state": "unresponsive",
+                    "reason": reason,
+                })),
+            )
+            .await;
+    }
+
+    async fn responsive(&mut self) {
+        self.healthy.store(true, Ordering::SeqCst);
+        log::info!("VM responsive again");
+        self.emitter
+            .state("vm", Some(serde_json::json!({ "state": "running" })))
+            .await;
+    }
+
+    /// Reports the first failure, unless the VM is being stopped
+    async fn fail(&mut self, reason: String) {
+        self.healthy.store(false, Ordering::SeqCst);
+        if self.failed {
+            return;
+        }
+        self.failed = true;
+        if self.stopping.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let console = self.console.tail();
+        log::error!(
+            "VM failure: {}. Serial console:\n{}",
+            reason,
+            console.join("\n")
+        );
+        self.emitter
+            .state(
+                "vm",
+                Some(serde_json::json!({
+                    "state": "failed",
+                    "reason": reason,
+                    "console": console,
+                })),
+            )
+            .await;
+    }
+}
+
+/// Pings the guest agent until its responsiveness changes. A `responsive` agent
+/// becomes unresponsive after failing to respond too many times in a row, an
+/// unresponsive one becomes responsive with the first successful ping.
+async fn heartbeat(ga: Arc<GuestAgent>, responsive: bool) -> Result<(), String> {
+    let mut missed = 0;
+    loop {
+        time::sleep(HEARTBEAT_INTERVAL).await;
+        match ga.ping(HEARTBEAT_TIMEOUT).await {
+            Ok(()) if !responsive => return Ok(()),
+            Ok(()) => missed = 0,
+            Err(err) => {
+                log::warn!("Guest agent ping failed: {}", err);
+                missed += 1;
+            }
+        }
+        if responsive && missed == HEARTBEAT_MAX_MISSED {
+            return Err(format!("Guest agent not responding to {} pings", missed));
+        }
+    }
+}
@@ -1,38 +1,73 @@
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
-use std::sync::atomic::AtomicU32;
-use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::Ordering::{Relaxed, SeqCst};
+use std::sync::atomic::{AtomicBool, AtomicU32};
 use std::sync::Arc;
 
 use futures::lock::Mutex;
 use futures::FutureExt;
 use tokio::io::AsyncBufReadExt;
-use tokio::{io, process, spawn};
+use tokio::{io, spawn, time};
 
 use ya_runtime_sdk::runtime_api::server;
 use ya_runtime_sdk::server::ContainerEndpoint;
 use ya_runtime_sdk::{serialize, ErrorExt, EventEmitter};
 
-use crate::deploy::Deployment;
-use crate::guest_agent_comm::{GuestAgent, Notification};
+use crate::deploy::{Deployment, OverlayUpper};
+use crate::detect_pci::PciAddress;
+use crate::gpu;
+use crate::guest_agent_comm::{
+    GuestAgent, GuestAgentError, Notification, RemoteCommandResult, Timeouts,
+};
+use crate::output::{OutputMode, OutputReader};
+use crate::qemu::{Chardev, Device, Drive, Netdev, NetdevSocket, Virtfs, VmConfig};
+use crate::qmp::QmpClient;
+use crate::supervisor::{remove_sockets, supervise, ConsoleLog, VmHandle};
 
 const DIR_RUNTIME: &'static str = "runtime";
 const FILE_RUNTIME: &'static str = "vmrt";
 const FILE_VMLINUZ: &'static str = "vmlinuz-virt";
 const FILE_INITRAMFS: &'static str = "initramfs.cpio.gz";
 
+#[cfg(target_arch = "aarch64")]
+const MACHINE: Option<&'static str> = Some("virt,gic-version=host");
+#[cfg(not(target_arch = "aarch64"))]
+const MACHINE: Option<&'static str> = None;
+
+#[cfg(target_arch = "aarch64")]
+const KERNEL_CONSOLE: &'static str = "ttyAMA0";
+#[cfg(not(target_arch = "aarch64"))]
+const KERNEL_CONSOLE: &'static str = "ttyS0";
+
 #[derive(Default)]
 pub struct RuntimeData {
-    pub runtime: Option<process::Child>,
+    pub runtime: Option<VmHandle>,
     pub vpn: Option<ContainerEndpoint>,
     pub inet: Option<ContainerEndpoint>,
     pub deployment: Option<Deployment>,
-    pub ga: Option<Arc<Mutex<GuestAgent>>>,
+    pub ga: Option<Arc<GuestAgent>>,
+    /// QEMU control channel
+    pub qmp: Option<Arc<QmpClient>>,
+    /// Attach a stdin pipe to spawned processes
+    pub interactive: bool,
+    /// Default stdout redirection of spawned processes
+    pub stdout: OutputMode,
+    /// Default stderr redirection of spawned processes
+    pub stderr: OutputMode,
+    /// GPUs passed through to the VM
+    pub gpus: Vec<PciAddress>,
+    /// Guest agent response deadlines
+    pub timeouts: Timeouts,
+    /// Output of the spawned processes
+    pub output: Arc<OutputReader>,
+    /// Id of the entrypoint process, 0 until it is started. Locked while spawning
+    /// a process until it is registered, so that its exit is handled afterwards.
+    pub entrypoint: Arc<Mutex<u64>>,
 }
 
 impl RuntimeData {
-    pub fn runtime(&mut self) -> anyhow::Result<process::Child> {
+    pub fn runtime(&mut self) -> anyhow::Result<VmHandle> {
         self.runtime
             .take()
             .ok_or_else(|| anyhow::anyhow!("Runtime process not available"))
@@ -44,7 +79,7 @@ impl RuntimeData {
             .ok_or_else(|| anyhow::anyhow!("Runtime not deployed"))
     }
 
-    pub fn ga(&self) -> anyhow::Result<Arc<Mutex<GuestAgent>>> {
+    pub fn ga(&self) -> anyhow::Result<Arc<GuestAgent>> {
         self.ga
             .clone()
             .ok_or_else(|| anyhow::anyhow!("Runtime not started"))
@@ -65,75 +100,78 @@ pub async fn start_vmrt(
     let volumes = deployment.volumes.clone();
 
     let manager_sock = temp_dir.join(format!("{}.sock", uid));
+    let qmp_sock = temp_dir.join(format!("{}_qmp.sock", uid));
     let vpn_remote = data.vpn.clone();
     let inet_remote = data.inet.clone();
 
-    let mut cmd = process::Command::new(runtime_dir.join(FILE_RUNTIME));
-    cmd.current_dir(&runtime_dir);
-    cmd.args(&[
-        "-m",
-        format!("{}M", deployment.mem_mib).as_str(),
-        "-nographic",
-        "-kernel",
-        FILE_VMLINUZ,
-        "-initrd",
-        FILE_INITRAMFS,
-        "-enable-kvm",
-        "-cpu",
-        "host",
-        "-smp",
-        deployment.cpu_cores.to_string().as_str(),
-        "-append",
-        "console=ttyS0 panic=1",
-        "-device",
-        "virtio-serial",
-        "-device",
-        "virtio-rng-pci",
-        "-chardev",
-        format!(
-            "socket,path={},server=on,wait=off,id=manager_cdev",
-            manager_sock.display()
-        )
-        .as_str(),
-        "-device",
-        "virtserialport,chardev=manager_cdev,name=manager_port",
-        "-drive",
-        format!(
-            "file={},cache=unsafe,readonly=on,format=raw,if=virtio",
-            deployment.task_package.display()
-        )
-        .as_str(),
-        "-no-reboot",
-    ]);
-
-    match std::env::var("GPU_PCI") {
-        Ok(val) => {
-            if val != "no" {
-                cmd.arg("-device");
-                cmd.arg(format!("vfio-pci,host={}", val).as_str());
-            }
-        },
-        Err(_e) => {
-            cmd.arg("-vga");
-            cmd.arg("none");
+    let mut config = VmConfig {
+        machine: MACHINE.map(ToString::to_string),
+        kvm: true,
+        cpu: Some("host".to_string()),
+        cpus: deployment.cpu_cores,
+        memory_mib: deployment.mem_mib,
+        kernel: FILE_VMLINUZ.into(),
+        initrd: FILE_INITRAMFS.into(),
+        kernel_args: vec![
+            format!("console={}", KERNEL_CONSOLE),
+            "panic=1".to_string(),
+            format!("overlay_upper={}", deployment.overlay),
+        ],
+        no_reboot: true,
+        qmp: Some(qmp_sock.clone()),
+        ..Default::default()
+    };
+    config
+        .device(Device::VirtioSerial)
+        .device(Device::VirtioRng)
+        .device(Device::VirtioBalloon)
+        .chardev(Chardev {
+            id: "manager_cdev".to_string(),
+            path: manager_sock.clone(),
+        })
+        .device(Device::VirtSerialPort {
+            chardev: "manager_cdev".to_string(),
+            name: "manager_port".to_string(),
+        })
+        .drive(Drive {
+            file: deployment.task_package.clone(),
+            readonly: true,
+        });
+
+    match deployment.storage(&work_dir) {
+        Some(storage) => {
+            config.drive(Drive {
+                file: storage,
+                readonly: false,
+            });
+        }
+        None if deployment.overlay == OverlayUpper::Disk => {
+            anyhow::bail!("Disk-backed overlay requires storage");
         }
+        None => (),
+    }
+
+    if data.gpus.is_empty() {
+        config.vga = Some("none".to_string());
+    }
+    for address in data.gpus.iter() {
+        gpu::check_passthrough(*address)?;
+        config.device(Device::VfioPci { host: *address });
     }
 
     let (vpn, inet) =
     // backward-compatibility mode
     if vpn_remote.is_none() && inet_remote.is_none() {
-        cmd.args(["-net", "none"]);
-
-        let vpn = configure_chardev_endpoint(&mut cmd, "vpn", &temp_dir, &uid)?;
-        let inet = configure_chardev_endpoint(&mut cmd, "inet", &temp_dir, &uid)?;
+        let vpn = configure_chardev_endpoint(&mut config, "vpn", &temp_dir, &uid)?;
+        let inet = configure_chardev_endpoint(&mut config, "inet", &temp_dir, &uid)?;
         (vpn, inet)
     // virtio-net (preferred)
     } else {
         let mut pair = SocketPairConf::default();
         pair.probe().await?;
 
-        let vpn = configure_netdev_endpoint(&mut cmd, "vpn", &vpn_remote, pair.first)?;
-        let inet = configure_netdev_endpoint(&mut cmd, "inet", &inet_remote, pair.second)?;
+        let vpn = configure_netdev_endpoint(&mut config, "vpn", &vpn_remote, pair.first)?;
+        let inet = configure_netdev_endpoint(&mut config, "inet", &inet_remote, pair.second)?;
         (vpn, inet)
     };
 
@@ -141,14 +179,15 @@ pub async fn start_vmrt(
     data.inet.replace(inet);
 
     for (idx, volume) in volumes.iter().enumerate() {
-        cmd.arg("-virtfs");
-        cmd.arg(format!(
-            "local,id={tag},path={path},security_model=none,mount_tag={tag}",
-            tag = format!("mnt{}", idx),
-            path = work_dir.join(&volume.name).to_string_lossy(),
-        ));
+        config.virtfs(Virtfs {
+            tag: format!("mnt{}", idx),
+            path: work_dir.join(&volume.name),
+        });
     }
 
+    let mut cmd = config.command(runtime_dir.join(FILE_RUNTIME));
+    cmd.current_dir(&runtime_dir);
+
     log::info!("Executing command: {cmd:?}");
 
     let mut runtime = cmd
@@ -157,34 +196,77 @@ pub async fn start_vmrt(
         .kill_on_drop(true)
         .spawn()?;
 
+    let console = Arc::new(ConsoleLog::default());
     let stdout = runtime.stdout.take().unwrap();
-    spawn(reader_to_log(stdout));
+    spawn(reader_to_log(stdout, console.clone()));
+
+    let mut sockets: Vec<_> = config.chardevs.iter().map(|c| c.path.clone()).collect();
+    sockets.push(qmp_sock.clone());
+    let sockets = SocketsGuard(sockets);
+
+    // QEMU is expected to be up and responsive within the connect timeout
+    let deadline = time::Instant::now() + data.timeouts.connect;
+    let qmp = QmpClient::connected(qmp_sock, data.timeouts.connect).await?;
+    let status = time::timeout_at(deadline, qmp.query_status())
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out querying the VM status"))?;
+    match status? {
+        Ok(status) => log::debug!("VM status: {}", status.status),
+        Err(e) => log::warn!("Unable to query VM status: {}", e),
+    }
 
-    let ga = GuestAgent::connected(manager_sock, 10, move |notification, ga| {
-        let mut emitter = emitter.clone();
-        async move {
-            let status = notification_into_status(notification, ga).await;
-            emitter.emit(status).await;
-        }
-        .boxed()
+    let output = data.output.clone();
+    let supervisor_emitter = emitter.clone();
+    let timeouts = data.timeouts.clone();
+    let entrypoint = data.entrypoint.clone();
+    // set once the VM shutdown is expected, e.g. after the entrypoint exits
+    let stopping = Arc::new(AtomicBool::new(false));
+    let ga_stopping = stopping.clone();
+    let ga = GuestAgent::connected(manager_sock, timeouts, move |notification, ga| {
+        let emitter = emitter.clone();
+        let output = output.clone();
+        let entrypoint = entrypoint.clone();
+        let stopping = ga_stopping.clone();
+        handle_notification(notification, ga, output, entrypoint, stopping, emitter).boxed()
     })
     .await?;
 
-    {
-        let mut ga = ga.lock().await;
-        for (idx, volume) in deployment.volumes.iter().enumerate() {
-            ga.mount(format!("mnt{}", idx).as_str(), volume.path.as_str())
-                .await?
-                .expect("Mount failed");
-        }
+    for (idx, volume) in deployment.volumes.iter().enumerate() {
+        ga.mount(format!("mnt{}", idx).as_str(), volume.path.as_str())
+            .await?;
     }
 
+    let runtime = supervise(
+        runtime,
+        ga.clone(),
+        console,
+        sockets.release(),
+        stopping,
+        supervisor_emitter,
+    );
     data.runtime.replace(runtime);
     data.ga.replace(ga);
+    data.qmp.replace(qmp);
 
     Ok(None)
 }
 
+/// Removes the sockets of a VM which failed to start, unless they are released
+/// to the supervisor
+struct SocketsGuard(Vec<PathBuf>);
+
+impl SocketsGuard {
+    fn release(mut self) -> Vec<PathBuf> {
+        std::mem::take(&mut self.0)
+    }
+}
+
+impl Drop for SocketsGuard {
+    fn drop(&mut self) {
+        remove_sockets(&self.0);
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 struct SocketConf {
     ip: Ipv4Addr,
@@ -228,27 +310,28 @@ impl SocketPairConf {
 }
 
 fn configure_chardev_endpoint(
-    cmd: &mut process::Command,
+    config: &mut VmConfig,
     id: &str,
     temp_dir: impl AsRef<Path>,
     uid: &str,
 ) -> anyhow::Result<ContainerEndpoint> {
     let sock = temp_dir.as_ref().join(format!("{}_{}.sock", uid, id));
 
-    cmd.arg("-chardev");
-    cmd.arg(format!(
-        "socket,path={},server,wait=off,id={id}_cdev",
-        sock.display()
-    ));
-
-    cmd.arg("-device");
-    cmd.arg(format!("virtserialport,chardev={id}_cdev,name={id}_port"));
+    config
+        .chardev(Chardev {
+            id: format!("{id}_cdev"),
+            path: sock.clone(),
+        })
+        .device(Device::VirtSerialPort {
+            chardev: format!("{id}_cdev"),
+            name: format!("{id}_port"),
+        });
 
     Ok(ContainerEndpoint::UnixStream(sock))
 }
 
 fn configure_netdev_endpoint(
-    cmd: &mut process::Command,
+    config: &mut VmConfig,
     id: &str,
     endpoint: &Option<ContainerEndpoint>,
     conf: SocketConf,
@@ -256,51 +339,49 @@ fn configure_netdev_endpoint(
     static COUNTER: AtomicU32 = AtomicU32::new(1);
 
     let ipv4 = conf.ip;
-    let endpoint = if let Some(endpoint) = endpoint {
+    let (socket, endpoint) = if let Some(endpoint) = endpoint {
         match endpoint {
             ContainerEndpoint::UdpDatagram(remote_addr) => {
-                let port = conf.udp;
-
-                cmd.arg("-netdev");
-                cmd.arg(format!(
-                    "socket,id={id},udp={remote_addr},localaddr={ipv4}:{port}"
-                ));
-
-                ContainerEndpoint::UdpDatagram(SocketAddrV4::new(ipv4, port).into())
-            }
-            ContainerEndpoint::TcpStream(remote_addr) => {
-                cmd.arg("-netdev");
-                cmd.arg(format!("socket,id={id},connect={remote_addr}"));
-
-                ContainerEndpoint::TcpStream(*remote_addr)
+                let local: SocketAddr = SocketAddrV4::new(ipv4, conf.udp).into();
+                let socket = NetdevSocket::Udp {
+                    remote: *remote_addr,
+                    local,
+                };
+                (socket, ContainerEndpoint::UdpDatagram(local))
             }
+            ContainerEndpoint::TcpStream(remote_addr) => (
+                NetdevSocket::Connect(*remote_addr),
+                ContainerEndpoint::TcpStream(*remote_addr),
+            ),
             ContainerEndpoint::TcpListener(_) => {
-                let port = conf.tcp;
-
-                cmd.arg("-netdev");
-                cmd.arg(format!("socket,id={id},listen={ipv4}:{port}"));
-
-                ContainerEndpoint::TcpStream(SocketAddrV4::new(ipv4, port).into())
+                let local: SocketAddr = SocketAddrV4::new(ipv4, conf.tcp).into();
+                (
+                    NetdevSocket::Listen(local),
+                    ContainerEndpoint::TcpStream(local),
+                )
             }
             _ => return Err(anyhow::anyhow!("Unsupported remote VPN VM endpoint")),
         }
     } else {
-        let port = conf.tcp;
-
-        cmd.arg("-netdev");
-        cmd.arg(format!("socket,id={id},listen={ipv4}:{port}"));
-
-        ContainerEndpoint::TcpListener(SocketAddrV4::new(ipv4, port).into())
+        let local: SocketAddr = SocketAddrV4::new(ipv4, conf.tcp).into();
+        (
+            NetdevSocket::Listen(local),
+            ContainerEndpoint::TcpListener(local),
+        )
     };
 
     let counter = COUNTER.fetch_add(1, Relaxed);
     let bytes = counter.to_be_bytes();
 
-    cmd.arg("-device");
-    cmd.arg(format!(
-        "virtio-net-pci,netdev={id},mac=90:13:{:0x}",
-        HexWriter(&bytes),
-    ));
+    config
+        .netdev(Netdev {
+            id: id.to_string(),
+            socket,
+        })
+        .device(Device::VirtioNet {
+            netdev: id.to_string(),
+            mac: format!("90:13:{:0x}", HexWriter(&bytes)),
+        });
 
     Ok(endpoint)
 }
@@ -329,7 +410,7 @@ pub fn runtime_dir() -> io::Result<PathBuf> {
         .join(DIR_RUNTIME))
 }
 
-async fn reader_to_log<T: io::AsyncRead + Unpin>(reader: T) {
+async fn reader_to_log<T: io::AsyncRead + Unpin>(reader: T, console: Arc<ConsoleLog>) {
     let mut reader = io::BufReader::new(reader);
     let mut buf = Vec::new();
     loop {
@@ -337,7 +418,9 @@ async fn reader_to_log<T: io::AsyncRead + Unpin>(reader: T) {
             Ok(0) => break,
             Ok(_) => {
                 let bytes = strip_ansi_escapes::strip(&buf).unwrap();
-                log::debug!("VM: {}", String::from_utf8_lossy(&bytes).trim_end());
+                let line = String::from_utf8_lossy(&bytes).trim_end().to_string();
+                log::debug!("VM: {}", line);
+                console.push(line);
                 buf.clear();
             }
             Err(e) => log::error!("VM output error: {}", e),
@@ -345,55 +428,114 @@ async fn reader_to_log<T: io::AsyncRead + Unpin>(reader: T) {
     }
 }
 
-async fn notification_into_status(
+async fn handle_notification(
     notification: Notification,
-    ga: Arc<Mutex<GuestAgent>>,
-) -> server::ProcessStatus {
+    ga: Arc<GuestAgent>,
+    output: Arc<OutputReader>,
+    entrypoint: Arc<Mutex<u64>>,
+    stopping: Arc<AtomicBool>,
+    mut emitter: EventEmitter,
+) {
     match notification {
         Notification::OutputAvailable { id, fd } => {
             log::debug!("Process {} has output available on fd {}", id, fd);
 
-            let output = {
-                let result = {
-                    let mut guard = ga.lock().await;
-                    guard.query_output(id, fd as u8, 0, u64::MAX).await
-                };
-                match result {
-                    Ok(Ok(vec)) => vec,
-                    Ok(Err(e)) => {
-                        log::error!("Remote error while querying output: {:?}", e);
-                        Vec::new()
-                    }
-                    Err(e) => {
-                        log::error!("Error querying output: {:?}", e);
-                        Vec::new()
-                    }
-                }
-            };
-            let (stdout, stderr) = match fd {
-                1 => (output, Vec::new()),
-                _ => (Vec::new(), output),
-            };
-
-            server::ProcessStatus {
-                pid: id,
-                running: true,
-                return_code: 0,
-                stdout,
-                stderr,
+            if let Err(e) = forward_output(&ga, &output, &mut emitter, id, fd as u8).await {
+                log_output_error(&e, &stopping);
             }
         }
         Notification::ProcessDied { id, reason } => {
             log::debug!("Process {} died with {:?}", id, reason);
 
-            // TODO: reason._type ?
-            server::ProcessStatus {
-                pid: id,
-                running: false,
-                return_code: reason.status as i32,
-                stdout: Vec::new(),
-                stderr: Vec::new(),
+            // waits for the process to be registered, in case it's being spawned
+            let is_entrypoint = id == *entrypoint.lock().await;
+            // the guest powers off once the entrypoint output is read, which is not a failure
+            if is_entrypoint {
+                stopping.store(true, SeqCst);
+            }
+
+            // the guest keeps a dead process until its buffered output is read
+            for fd in output.drained_fds(id) {
+                match forward_output(&ga, &output, &mut emitter, id, fd).await {
+                    Ok(()) => (),
+                    Err(e) if e.errno() == Some(libc::ESRCH as u32) => (),
+                    Err(e) => log_output_error(&e, &stopping),
+                }
             }
+            output.remove(id);
+
+            emitter
+                .emit(server::ProcessStatus {
+                    pid: id,
+                    running: false,
+                    return_code: reason.return_code(),
+                    stdout: Vec::new(),
+                    stderr: Vec::new(),
+                })
+                .await;
+
+            if is_entrypoint {
+                emitter
+                    .state(
+                        "entrypoint",
+                        Some(serde_json::json!({
+                            "state": "terminated",
+                            "pid": id,
+                            "return_code": reason.return_code(),
+                        })),
+                    )
+                    .await;
+            }
+        }
+    }
+}
+
+/// Output queries in flight fail once the guest powers off, which is expected while stopping
+fn log_output_error(e: &GuestAgentError, stopping: &AtomicBool) {
+    match e {
+        GuestAgentError::Closed(_) if stopping.load(SeqCst) => {
+            log::debug!("Error querying output: {}", e)
+        }
+        _ => log::error!("Error querying output: {}", e),
+    }
+}
+
+/// Emits process output available on `fd`, until there is no more
+async fn forward_output(
+    ga: &GuestAgent,
+    output: &OutputReader,
+    emitter: &mut EventEmitter,
+    id: u64,
+    fd: u8,
+) -> RemoteCommandResult<()> {
+    // emitting each chunk before querying the next one throttles chatty processes
+    loop {
+        let read = output.read(ga, id, fd).await?;
+        if read.dropped > 0 {
+            log::warn!(
+                "Process {} output on fd {}: {} B dropped",
+                id,
+                fd,
+                read.dropped
+            );
+        }
+        if !read.data.is_empty() {
+            let (stdout, stderr) = match fd {
+                1 => (read.data, Vec::new()),
+                _ => (Vec::new(), read.data),
+            };
+            emitter
+                .emit(server::ProcessStatus {
+                    pid: id,
+                    running: true,
+                    return_code: 0,
+                    stdout,
+                    stderr,
+                })
+                .await;
+        }
+        if !read.more {
+            return Ok(());
         }
     }
 }